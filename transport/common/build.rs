@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // vendored protoc, so the build doesn't depend on a system protobuf compiler
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+
+    tonic_build::compile_protos("proto/transport.proto")?;
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use tonic::Status;
+
+use crate::{Segment, SegmentWithTime};
+
+// Generated from proto/transport.proto
+pub mod proto {
+    tonic::include_proto!("transport");
+}
+
+impl From<Segment> for proto::Segment {
+    fn from(value: Segment) -> Self {
+        Self {
+            payload: value.payload,
+            seg_count: value.seg_count as u64,
+            seg_num: value.seg_num as u64,
+            sender: value.sender,
+        }
+    }
+}
+
+impl From<proto::Segment> for Segment {
+    fn from(value: proto::Segment) -> Self {
+        Self {
+            payload: value.payload,
+            seg_count: value.seg_count as usize,
+            seg_num: value.seg_num as usize,
+            sender: value.sender,
+        }
+    }
+}
+
+impl From<SegmentWithTime> for proto::SegmentWithTime {
+    fn from(value: SegmentWithTime) -> Self {
+        Self {
+            segment: Some(value.segment.into()),
+            send_time: value.send_time,
+        }
+    }
+}
+
+impl TryFrom<proto::SegmentWithTime> for SegmentWithTime {
+    type Error = Status;
+
+    fn try_from(value: proto::SegmentWithTime) -> Result<Self, Self::Error> {
+        let segment = value
+            .segment
+            .ok_or_else(|| Status::invalid_argument("segment is required"))?;
+
+        Ok(Self {
+            segment: segment.into(),
+            send_time: value.send_time,
+        })
+    }
+}
@@ -5,6 +5,8 @@ use rdkafka::message::{Headers, OwnedHeaders, OwnedMessage};
 use rdkafka::producer::FutureRecord;
 use rdkafka::Message;
 
+use anyhow::anyhow;
+
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -13,6 +15,10 @@ use env_logger::fmt::Formatter;
 use env_logger::Builder;
 use log::{LevelFilter, Record};
 
+pub mod grpc;
+pub mod pattern;
+pub mod topic;
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Segment {
     pub payload: Vec<u8>,
@@ -32,7 +38,7 @@ impl SegmentWithTime {
     pub fn into_future_record<'a>(
         &'a self,
         topic_name: &'a str,
-    ) -> FutureRecord<'_, String, Vec<u8>> {
+    ) -> FutureRecord<'a, String, Vec<u8>> {
         FutureRecord::to(topic_name)
             .payload(&self.segment.payload)
             .key(&self.send_time)
@@ -49,31 +55,36 @@ impl From<Segment> for OwnedHeaders {
     }
 }
 
-impl From<OwnedMessage> for SegmentWithTime {
-    fn from(value: OwnedMessage) -> Self {
-        let send_time: String = String::from_utf8(value.key().unwrap().to_vec()).unwrap();
-
-        let headers = value.headers().unwrap();
-
-        let seg_count = usize::from_ne_bytes(
-            headers
-                .get(0)
-                .unwrap()
-                .1
-                .try_into()
-                .expect("Incorrect byte slice length"),
-        );
-        let seg_num = usize::from_ne_bytes(
-            headers
-                .get(1)
-                .unwrap()
-                .1
+// Broken records from the topic give an error instead of a panic
+impl TryFrom<OwnedMessage> for SegmentWithTime {
+    type Error = anyhow::Error;
+
+    fn try_from(value: OwnedMessage) -> Result<Self, Self::Error> {
+        let key = value.key().ok_or_else(|| anyhow!("no key"))?;
+        let send_time =
+            String::from_utf8(key.to_vec()).map_err(|e| anyhow!("key is not utf-8: {}", e))?;
+
+        let headers = value.headers().ok_or_else(|| anyhow!("no headers"))?;
+        let header = |name: &str| {
+            (0..headers.count())
+                .filter_map(|i| headers.get(i))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| anyhow!("no {} header", name))
+        };
+        let usize_header = |name: &str| -> anyhow::Result<usize> {
+            let bytes = header(name)?
                 .try_into()
-                .expect("Incorrect byte slice length"),
-        );
-        let sender = String::from_utf8(headers.get(2).unwrap().1.to_vec()).unwrap();
+                .map_err(|_| anyhow!("{} header has wrong length", name))?;
+            Ok(usize::from_ne_bytes(bytes))
+        };
+
+        let seg_count = usize_header("seg_count")?;
+        let seg_num = usize_header("seg_num")?;
+        let sender = String::from_utf8(header("sender")?.to_vec())
+            .map_err(|e| anyhow!("sender is not utf-8: {}", e))?;
 
-        let payload = value.payload().unwrap().to_vec();
+        let payload = value.payload().unwrap_or_default().to_vec();
 
         let segment = Segment {
             payload,
@@ -82,7 +93,7 @@ impl From<OwnedMessage> for SegmentWithTime {
             sender,
         };
 
-        Self { send_time, segment }
+        Ok(Self { send_time, segment })
     }
 }
 
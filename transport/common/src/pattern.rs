@@ -0,0 +1,105 @@
+use regex::Regex;
+use serde::Deserialize;
+
+// Sender matcher used in routing configs, deserialized from
+// {"exact": "..."}, {"glob": "team-*"} or {"regex": "^team-[ab]$"}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "PatternDef")]
+pub enum Pattern {
+    Exact(String),
+    Glob(Regex),
+    Regex(Regex),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PatternDef {
+    Exact(String),
+    Glob(String),
+    Regex(String),
+}
+
+impl TryFrom<PatternDef> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(value: PatternDef) -> Result<Self, Self::Error> {
+        match value {
+            PatternDef::Exact(s) => Ok(Pattern::Exact(s)),
+            PatternDef::Glob(s) => Pattern::glob(&s),
+            PatternDef::Regex(s) => Ok(Pattern::Regex(Regex::new(&s)?)),
+        }
+    }
+}
+
+impl Pattern {
+    // `*` matches any sequence of characters, `?` matches exactly one
+    pub fn glob(glob: &str) -> Result<Self, regex::Error> {
+        let escaped = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+
+        Ok(Pattern::Glob(Regex::new(&format!("^{}$", escaped))?))
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Exact(s) => s == value,
+            Pattern::Glob(r) | Pattern::Regex(r) => r.is_match(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_star_matches_any_sequence() {
+        let pattern = Pattern::glob("team-*").unwrap();
+
+        assert!(pattern.matches("team-"));
+        assert!(pattern.matches("team-a"));
+        assert!(pattern.matches("team-a-b"));
+        assert!(!pattern.matches("team"));
+    }
+
+    #[test]
+    fn glob_question_mark_matches_one_character() {
+        let pattern = Pattern::glob("team-?").unwrap();
+
+        assert!(pattern.matches("team-a"));
+        assert!(!pattern.matches("team-"));
+        assert!(!pattern.matches("team-ab"));
+    }
+
+    #[test]
+    fn glob_is_anchored_and_literal() {
+        let pattern = Pattern::glob("a.b").unwrap();
+
+        assert!(pattern.matches("a.b"));
+        assert!(!pattern.matches("axb"));
+        assert!(!pattern.matches("xa.b"));
+        assert!(!pattern.matches("a.bx"));
+    }
+
+    #[test]
+    fn empty_patterns_match_only_empty_values() {
+        assert!(Pattern::glob("").unwrap().matches(""));
+        assert!(!Pattern::glob("").unwrap().matches("a"));
+        assert!(Pattern::Exact(String::new()).matches(""));
+        assert!(!Pattern::Exact(String::new()).matches("a"));
+    }
+
+    #[test]
+    fn regex_is_not_anchored() {
+        let pattern = Pattern::try_from(PatternDef::Regex("team-[ab]".to_owned())).unwrap();
+
+        assert!(pattern.matches("team-a"));
+        assert!(pattern.matches("my-team-b"));
+        assert!(!pattern.matches("team-c"));
+    }
+
+    #[test]
+    fn broken_regex_is_an_error() {
+        assert!(Pattern::try_from(PatternDef::Regex("team-(".to_owned())).is_err());
+        assert!(Pattern::try_from(PatternDef::Glob("team-(".to_owned())).is_ok());
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, ResourceSpecifier, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::ClientConfig;
+
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TopicSpec {
+    pub partitions: i32,
+    pub replication_factor: i32,
+    pub retention_ms: Option<i64>,
+    pub cleanup_policy: Option<String>,
+}
+
+impl TopicSpec {
+    fn configs(&self) -> Vec<(&'static str, String)> {
+        let mut configs = vec![];
+        if let Some(retention_ms) = self.retention_ms {
+            configs.push(("retention.ms", retention_ms.to_string()));
+        }
+        if let Some(cleanup_policy) = &self.cleanup_policy {
+            configs.push(("cleanup.policy", cleanup_policy.clone()));
+        }
+        configs
+    }
+}
+
+// Creates missing topics, existing ones are checked to be compatible with the spec
+pub async fn ensure_topics(brokers: &str, topics: &[&str], spec: &TopicSpec) -> Result<()> {
+    let admin: AdminClient<DefaultClientContext> = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .create()?;
+
+    let metadata = admin.inner().fetch_metadata(None, ADMIN_TIMEOUT)?;
+    let opts = AdminOptions::new().operation_timeout(Some(ADMIN_TIMEOUT));
+
+    for &topic in topics {
+        match metadata.topics().iter().find(|t| t.name() == topic) {
+            Some(existing) => {
+                let partitions = existing.partitions();
+                let replication_factor = partitions
+                    .first()
+                    .map(|p| p.replicas().len() as i32)
+                    .unwrap_or(0);
+
+                if partitions.len() as i32 != spec.partitions {
+                    bail!(
+                        "topic {} has {} partitions, expected {}",
+                        topic,
+                        partitions.len(),
+                        spec.partitions
+                    );
+                }
+                if replication_factor != spec.replication_factor {
+                    bail!(
+                        "topic {} has replication factor {}, expected {}",
+                        topic,
+                        replication_factor,
+                        spec.replication_factor
+                    );
+                }
+
+                check_topic_configs(&admin, &opts, topic, spec).await?;
+
+                info!("topic {} exists and is compatible", topic);
+            }
+            None => {
+                let configs = spec.configs();
+                let new_topic = configs.iter().fold(
+                    NewTopic::new(
+                        topic,
+                        spec.partitions,
+                        TopicReplication::Fixed(spec.replication_factor),
+                    ),
+                    |new_topic, (key, value)| new_topic.set(key, value),
+                );
+
+                for result in admin.create_topics(&[new_topic], &opts).await? {
+                    result.map_err(|(name, code)| {
+                        anyhow!("failed to create topic {}: {:?}", name, code)
+                    })?;
+                }
+
+                info!("topic {} created with {:?}", topic, spec);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn check_topic_configs(
+    admin: &AdminClient<DefaultClientContext>,
+    opts: &AdminOptions,
+    topic: &str,
+    spec: &TopicSpec,
+) -> Result<()> {
+    let expected = spec.configs();
+    if expected.is_empty() {
+        return Ok(());
+    }
+
+    let resource = admin
+        .describe_configs(&[ResourceSpecifier::Topic(topic)], opts)
+        .await?
+        .pop()
+        .ok_or_else(|| anyhow!("no configs described for topic {}", topic))?
+        .map_err(|code| anyhow!("failed to describe topic {}: {:?}", topic, code))?;
+
+    for (key, value) in expected {
+        let actual = resource.get(key).and_then(|e| e.value.clone());
+        if actual.as_deref() != Some(value.as_str()) {
+            bail!(
+                "topic {} has {}={}, expected {}",
+                topic,
+                key,
+                actual.unwrap_or_default(),
+                value
+            );
+        }
+    }
+
+    Ok(())
+}
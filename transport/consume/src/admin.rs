@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::message_builder::PendingEntry;
+use crate::validation::Violation;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageRef {
+    pub sender: String,
+    pub send_time: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartitionRef {
+    pub topic: String,
+    pub partition: i32,
+}
+
+// Requests of the admin api, handled by the consume loop between segments
+// so they see and change the same state as it
+pub enum AdminCommand {
+    Pending(oneshot::Sender<Vec<PendingEntry>>),
+    Entry(MessageRef, oneshot::Sender<Option<PendingEntry>>),
+    // deliver as an error message, false when there is no such pending message
+    Flush(MessageRef, oneshot::Sender<bool>),
+    // forget without delivering, false when there is no such pending message
+    Drop(MessageRef, oneshot::Sender<bool>),
+    Pause(Vec<PartitionRef>, oneshot::Sender<Result<(), String>>),
+    Resume(Vec<PartitionRef>, oneshot::Sender<Result<(), String>>),
+    Quarantine(oneshot::Sender<Vec<Violation>>),
+}
+
+#[derive(Clone)]
+pub struct Admin {
+    tx: mpsc::Sender<AdminCommand>,
+}
+
+impl Admin {
+    pub fn channel(capacity: usize) -> (Self, mpsc::Receiver<AdminCommand>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Self { tx }, rx)
+    }
+
+    // None when the consume loop is gone
+    pub async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> AdminCommand,
+    ) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        self.tx.send(command(reply)).await.ok()?;
+        answer.await.ok()
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+const MAX_TRACKED_MESSAGES: usize = 100_000;
+const TRACK_WINDOW: Duration = Duration::from_secs(30 * 60);
+
+// topic, sender and send_time, the same message id may be used on several topics
+type TrackedMessage = (String, String, String);
+
+// Reassembly keeps segments of a message in the memory of one group member, so all the
+// segments of a message have to come from a single partition. Produce guarantees it with
+// any partition strategy (the default one hashes the send_time key). This check only
+// reports violations (logged and counted as misplaced segments), e.g. after a topic was
+// repartitioned while messages were in flight.
+pub struct ColocationCheck {
+    seen: HashMap<TrackedMessage, i32>,
+    // messages in the order they were first seen, the oldest ones are forgotten first
+    order: VecDeque<(Instant, TrackedMessage)>,
+}
+
+impl ColocationCheck {
+    pub fn new() -> Self {
+        Self {
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // returns false when the segment came from another partition than the previous ones
+    pub fn check(&mut self, topic: &str, sender: &str, send_time: &str, partition: i32) -> bool {
+        self.check_at(topic, sender, send_time, partition, Instant::now())
+    }
+
+    fn check_at(
+        &mut self,
+        topic: &str,
+        sender: &str,
+        send_time: &str,
+        partition: i32,
+        now: Instant,
+    ) -> bool {
+        self.forget_old(now);
+
+        let key = (topic.to_owned(), sender.to_owned(), send_time.to_owned());
+        let expected = match self.seen.get(&key) {
+            Some(expected) => *expected,
+            None => {
+                self.seen.insert(key.clone(), partition);
+                self.order.push_back((now, key));
+                return true;
+            }
+        };
+
+        if expected != partition {
+            warn!(
+                "segments of message {}/{} came from partitions {} and {} of {}, reassembly may fail",
+                sender, send_time, expected, partition, topic
+            );
+            return false;
+        }
+
+        true
+    }
+
+    fn forget_old(&mut self, now: Instant) {
+        while let Some((seen_at, _)) = self.order.front() {
+            if now.duration_since(*seen_at) < TRACK_WINDOW
+                && self.order.len() < MAX_TRACKED_MESSAGES
+            {
+                break;
+            }
+            if let Some((_, key)) = self.order.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_partition_is_fine() {
+        let mut check = ColocationCheck::new();
+
+        assert!(check.check("topic", "sender", "1", 0));
+        assert!(check.check("topic", "sender", "1", 0));
+    }
+
+    #[test]
+    fn split_message_is_reported() {
+        let mut check = ColocationCheck::new();
+
+        assert!(check.check("topic", "sender", "1", 0));
+        assert!(!check.check("topic", "sender", "1", 1));
+        // other messages are not affected
+        assert!(check.check("topic", "sender", "2", 1));
+    }
+
+    #[test]
+    fn same_id_on_other_topic_is_another_message() {
+        let mut check = ColocationCheck::new();
+
+        assert!(check.check("a", "sender", "1", 0));
+        assert!(check.check("b", "sender", "1", 1));
+    }
+
+    #[test]
+    fn messages_are_forgotten_after_the_window() {
+        let mut check = ColocationCheck::new();
+        let start = Instant::now();
+
+        assert!(check.check_at("topic", "sender", "1", 0, start));
+        assert!(check.check_at("topic", "sender", "1", 1, start + TRACK_WINDOW));
+        assert_eq!(check.seen.len(), 1);
+    }
+
+    #[test]
+    fn oldest_messages_make_room() {
+        let mut check = ColocationCheck::new();
+        let now = Instant::now();
+
+        for n in 0..MAX_TRACKED_MESSAGES + 1 {
+            check.check_at("topic", "sender", &n.to_string(), 0, now);
+        }
+
+        assert_eq!(check.seen.len(), MAX_TRACKED_MESSAGES);
+        assert!(!check.seen.contains_key(&(
+            "topic".to_owned(),
+            "sender".to_owned(),
+            "0".to_owned()
+        )));
+    }
+}
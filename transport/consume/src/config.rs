@@ -0,0 +1,498 @@
+use chrono::{DateTime, Utc};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use std::time::Duration;
+
+use common::topic::TopicSpec;
+
+use crate::delivery::RetryPolicy;
+use crate::message_builder::{CacheLimits, DeadlineFrom, EvictionPolicy, PartialDelivery};
+use crate::seek::{OffsetReset, PartitionOffset, ReplayWindow, StartPosition};
+use crate::sink::{Guarantee, Rotation, SinkKind};
+use crate::subscription::{SlowClientPolicy, SubscriptionLimits};
+
+const ADMIN_LISTEN_DEFAULT: &str = "127.0.0.1:8003";
+
+// Every flag can also be set by the CONSUME_ prefixed environment variable,
+// a flag given on the command line wins
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// broker list in kafka format
+    #[arg(short, long, env = "CONSUME_BROKERS", default_value = "localhost:9092")]
+    brokers: String,
+
+    #[arg(
+        short,
+        long,
+        env = "CONSUME_GROUP_ID",
+        default_value = "example_consumer_group_id"
+    )]
+    group_id: String,
+
+    /// topic list, comma separated
+    #[arg(
+        short,
+        long = "topic",
+        env = "CONSUME_TOPIC",
+        value_delimiter = ',',
+        required = true
+    )]
+    topics: Vec<String>,
+
+    /// url of receive service, messages are POSTed to it as json
+    #[arg(
+        short,
+        long = "receive_url",
+        alias = "receive-url",
+        env = "CONSUME_RECEIVE_URL"
+    )]
+    receive_url: Option<String>,
+
+    /// json file routing messages to http endpoints by sender, reloaded when changed
+    #[arg(long, env = "CONSUME_ROUTES_FILE")]
+    routes_file: Option<String>,
+
+    /// how often the routes file is checked for changes
+    #[arg(long, env = "CONSUME_ROUTES_RELOAD_SECS", default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    routes_reload_secs: u64,
+
+    /// topic to produce messages to as json, keyed by sender
+    #[arg(long, env = "CONSUME_OUTPUT_TOPIC")]
+    output_topic: Option<String>,
+
+    /// file to append messages to as json lines
+    #[arg(long, env = "CONSUME_JSONL_PATH")]
+    jsonl_path: Option<String>,
+
+    /// size the jsonl file is rotated at
+    #[arg(long, env = "CONSUME_JSONL_MAX_BYTES", default_value_t = 64 * 1024 * 1024,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    jsonl_max_bytes: u64,
+
+    /// rotated jsonl files kept, 0 drops the file on rotation
+    #[arg(long, env = "CONSUME_JSONL_MAX_FILES", default_value_t = 5)]
+    jsonl_max_files: usize,
+
+    /// print messages to stdout as json lines
+    #[arg(long, env = "CONSUME_STDOUT")]
+    stdout: bool,
+
+    /// unix domain socket to write messages to as json lines
+    #[arg(long, env = "CONSUME_UNIX_SOCKET")]
+    unix_socket: Option<String>,
+
+    /// address of the server with GET /subscribe streams
+    #[arg(long, env = "CONSUME_LISTEN")]
+    listen: Option<String>,
+
+    /// address of the /admin api server, keep it private, the api can drop pending messages
+    #[arg(long, env = "CONSUME_ADMIN_LISTEN", default_value = ADMIN_LISTEN_DEFAULT)]
+    admin_listen: String,
+
+    /// delivered messages kept for subscribers resuming from a cursor
+    #[arg(long, env = "CONSUME_SUBSCRIBE_HISTORY", default_value_t = 1000)]
+    subscribe_history: usize,
+
+    /// messages buffered for a subscriber before --slow-client-policy applies
+    #[arg(long, env = "CONSUME_SUBSCRIBE_BUFFER", default_value_t = 256,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    subscribe_buffer: u64,
+
+    #[arg(long, env = "CONSUME_SLOW_CLIENT_POLICY", value_enum, default_value_t = SlowClientPolicy::Disconnect)]
+    slow_client_policy: SlowClientPolicy,
+
+    /// sinks sent to once without retries and dead-lettering, comma separated
+    #[arg(
+        long,
+        env = "CONSUME_BEST_EFFORT_SINKS",
+        value_enum,
+        value_delimiter = ','
+    )]
+    best_effort_sinks: Vec<SinkKind>,
+
+    /// where partitions without committed offset of the group start
+    #[arg(long, env = "CONSUME_OFFSET_RESET", value_enum, default_value_t = OffsetReset::Latest)]
+    offset_reset: OffsetReset,
+
+    /// commit the start or the end of the topics for the group before joining it.
+    /// Like the other --start-* options it's refused while the group has running members
+    #[arg(long, env = "CONSUME_START_FROM", value_enum,
+        conflicts_with_all = ["start_time", "start_offset", "replay_from"])]
+    start_from: Option<OffsetReset>,
+
+    /// commit the offsets of segments produced at this time for the group before joining it,
+    /// rfc3339 or unix millis
+    #[arg(long, env = "CONSUME_START_TIME", value_parser = timestamp,
+        conflicts_with_all = ["start_offset", "replay_from"])]
+    start_time: Option<DateTime<Utc>>,
+
+    /// commit the offset of a partition for the group before joining it,
+    /// topic:partition:offset, comma separated
+    #[arg(long, env = "CONSUME_START_OFFSET", value_parser = partition_offset,
+        value_delimiter = ',', conflicts_with = "replay_from")]
+    start_offset: Vec<PartitionOffset>,
+
+    /// deliver messages sent from this time again and exit, rfc3339 or unix millis.
+    /// Partitions are assigned without joining the group and offsets aren't committed
+    #[arg(long, env = "CONSUME_REPLAY_FROM", value_parser = timestamp)]
+    replay_from: Option<DateTime<Utc>>,
+
+    /// end of the replayed window, now by default
+    #[arg(long, env = "CONSUME_REPLAY_TO", value_parser = timestamp, requires = "replay_from")]
+    replay_to: Option<DateTime<Utc>>,
+
+    /// session.timeout.ms of the consumer group member
+    #[arg(long, env = "CONSUME_SESSION_TIMEOUT_MS", default_value_t = 6000,
+        value_parser = clap::value_parser!(u32).range(1..=3_600_000))]
+    session_timeout_ms: u32,
+
+    /// max age of an incomplete message, counted by --deadline-from
+    #[arg(long, env = "CONSUME_MESSAGE_TIMEOUT_SECS", default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    message_timeout_secs: u64,
+
+    /// time the max age of an incomplete message is counted from, send-time gives up on
+    /// incomplete messages of a backlog older than the timeout at once. Replays count from
+    /// first-seen
+    #[arg(long, env = "CONSUME_DEADLINE_FROM", value_enum, default_value_t = DeadlineFrom::FirstSeen)]
+    deadline_from: DeadlineFrom,
+
+    /// how long records of messages are kept after their last segment was consumed,
+    /// late segments of older messages start a new reassembly
+    #[arg(long, env = "CONSUME_CACHE_TTL_SECS", default_value_t = 30 * 60,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    cache_ttl_secs: u64,
+
+    /// how often records idle for --cache-ttl-secs are cleaned
+    #[arg(long, env = "CONSUME_HOUSEKEEPING_INTERVAL_SECS", default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    housekeeping_interval_secs: u64,
+
+    /// how often offsets of delivered messages are committed
+    #[arg(long, env = "CONSUME_COMMIT_INTERVAL_MS", default_value_t = 5000,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    commit_interval_ms: u64,
+
+    /// incomplete messages kept in memory before eviction
+    #[arg(long, env = "CONSUME_MAX_PENDING_MESSAGES", default_value_t = 10_000,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    max_pending_messages: u64,
+
+    /// payload bytes of incomplete messages kept in memory before eviction
+    #[arg(long, env = "CONSUME_MAX_PENDING_BYTES", default_value_t = 256 * 1024 * 1024,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    max_pending_bytes: u64,
+
+    /// segments of messages with more segments are quarantined
+    #[arg(long, env = "CONSUME_MAX_SEG_COUNT", default_value_t = 100_000,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    max_seg_count: u64,
+
+    /// which incomplete message is evicted when a limit is reached
+    #[arg(long, env = "CONSUME_EVICTION_POLICY", value_enum, default_value_t = EvictionPolicy::Oldest)]
+    eviction_policy: EvictionPolicy,
+
+    /// don't compare payloads of duplicate segments
+    #[arg(long, env = "CONSUME_SKIP_DUPLICATE_CHECK")]
+    skip_duplicate_check: bool,
+
+    /// deliver timed out messages with the segments that arrived, marked as partial
+    #[arg(long, env = "CONSUME_PARTIAL_DELIVERY")]
+    partial_delivery: bool,
+
+    /// text put in place of every missing segment by --partial-delivery
+    #[arg(long, env = "CONSUME_GAP_PLACEHOLDER", requires = "partial_delivery")]
+    gap_placeholder: Option<String>,
+
+    /// directory to keep segments of incomplete messages across restarts
+    #[arg(long, env = "CONSUME_STATE_DIR")]
+    state_dir: Option<String>,
+
+    /// messages sent to the receive service at the same time, consuming waits for a free slot
+    #[arg(long, env = "CONSUME_MAX_CONCURRENT_DELIVERIES", default_value_t = 64,
+        value_parser = clap::value_parser!(u64).range(1..=1_000_000))]
+    max_concurrent_deliveries: u64,
+
+    /// topic for messages the at-least-once sinks didn't accept, without it they are retried until accepted
+    #[arg(long, env = "CONSUME_DEAD_LETTER_TOPIC")]
+    dead_letter_topic: Option<String>,
+
+    /// retries before a message goes to the dead-letter topic
+    #[arg(long, env = "CONSUME_SEND_MAX_RETRIES", default_value_t = 5)]
+    send_max_retries: u32,
+
+    /// delay before the first retry, doubled on every next one
+    #[arg(long, env = "CONSUME_SEND_RETRY_BACKOFF_MS", default_value_t = 500,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    send_retry_backoff_ms: u64,
+
+    /// upper bound of the retry delay
+    #[arg(long, env = "CONSUME_SEND_MAX_BACKOFF_MS", default_value_t = 30_000,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    send_max_backoff_ms: u64,
+
+    /// create the topics on startup if they don't exist, fail if existing ones don't match
+    #[arg(long, env = "CONSUME_ENSURE_TOPIC")]
+    ensure_topic: bool,
+
+    #[arg(long, env = "CONSUME_TOPIC_PARTITIONS", default_value_t = 1)]
+    topic_partitions: i32,
+
+    #[arg(long, env = "CONSUME_TOPIC_REPLICATION_FACTOR", default_value_t = 1)]
+    topic_replication_factor: i32,
+
+    #[arg(long, env = "CONSUME_TOPIC_RETENTION_MS")]
+    topic_retention_ms: Option<i64>,
+
+    #[arg(long, env = "CONSUME_TOPIC_CLEANUP_POLICY")]
+    topic_cleanup_policy: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub brokers: String,
+    pub group_id: String,
+    pub topics: Vec<String>,
+    pub receive_url: Option<String>,
+    pub routes_file: Option<String>,
+    pub routes_reload_interval: Duration,
+    pub output_topic: Option<String>,
+    pub jsonl_path: Option<String>,
+    pub jsonl_rotation: Rotation,
+    pub stdout: bool,
+    pub unix_socket: Option<String>,
+    pub best_effort_sinks: Vec<SinkKind>,
+    pub listen: Option<String>,
+    pub admin_listen: String,
+    pub subscription: SubscriptionLimits,
+    pub offset_reset: OffsetReset,
+    pub start: StartPosition,
+    pub replay: Option<ReplayWindow>,
+    pub session_timeout_ms: u32,
+    pub message_timeout: chrono::Duration,
+    pub deadline_from: DeadlineFrom,
+    pub cache_ttl: chrono::Duration,
+    pub housekeeping_interval: Duration,
+    pub commit_interval: Duration,
+    pub cache_limits: CacheLimits,
+    pub skip_duplicate_check: bool,
+    pub partial_delivery: Option<PartialDelivery>,
+    pub state_dir: Option<String>,
+    pub max_concurrent_deliveries: usize,
+    pub dead_letter_topic: Option<String>,
+    pub send_max_retries: u32,
+    pub retry: RetryPolicy,
+    pub ensure_topic: Option<TopicSpec>,
+}
+
+impl Config {
+    pub fn build() -> Self {
+        Self {
+            brokers: "".to_string(),
+            group_id: "".to_string(),
+            topics: vec![],
+            receive_url: None,
+            routes_file: None,
+            routes_reload_interval: Duration::from_secs(0),
+            output_topic: None,
+            jsonl_path: None,
+            jsonl_rotation: Rotation {
+                max_bytes: 0,
+                max_files: 0,
+            },
+            stdout: false,
+            unix_socket: None,
+            best_effort_sinks: vec![],
+            listen: None,
+            admin_listen: ADMIN_LISTEN_DEFAULT.to_owned(),
+            subscription: SubscriptionLimits {
+                history: 0,
+                buffer: 0,
+                slow_client: SlowClientPolicy::Disconnect,
+            },
+            offset_reset: OffsetReset::Latest,
+            start: StartPosition::Committed,
+            replay: None,
+            session_timeout_ms: 0,
+            message_timeout: chrono::Duration::zero(),
+            deadline_from: DeadlineFrom::FirstSeen,
+            cache_ttl: chrono::Duration::zero(),
+            housekeeping_interval: Duration::from_secs(0),
+            commit_interval: Duration::from_secs(0),
+            cache_limits: CacheLimits {
+                max_pending_messages: 0,
+                max_pending_bytes: 0,
+                max_seg_count: 0,
+                eviction_policy: EvictionPolicy::Oldest,
+            },
+            skip_duplicate_check: false,
+            partial_delivery: None,
+            state_dir: None,
+            max_concurrent_deliveries: 0,
+            dead_letter_topic: None,
+            send_max_retries: 0,
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_secs(0),
+                max_backoff: Duration::from_secs(0),
+            },
+            ensure_topic: None,
+        }
+    }
+
+    // Exits with the usage error when flags contradict each other
+    pub fn cmd_parse(mut self) -> Self {
+        let args = Args::parse();
+
+        let has_sink = args.receive_url.is_some()
+            || args.routes_file.is_some()
+            || args.output_topic.is_some()
+            || args.jsonl_path.is_some()
+            || args.stdout
+            || args.unix_socket.is_some()
+            || args.listen.is_some();
+        if !has_sink {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "no sink, set at least one of --receive_url, --routes-file, --output-topic, --jsonl-path, --stdout, --unix-socket, --listen",
+                )
+                .exit();
+        }
+        if args.send_retry_backoff_ms > args.send_max_backoff_ms {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--send-retry-backoff-ms is greater than --send-max-backoff-ms",
+                )
+                .exit();
+        }
+        if let (Some(from), Some(to)) = (args.replay_from, args.replay_to) {
+            if from > to {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--replay-from is after --replay-to",
+                    )
+                    .exit();
+            }
+        }
+        // clean drops pending records too, they'd be expired before their deadline
+        if args.cache_ttl_secs <= args.message_timeout_secs {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--cache-ttl-secs must be greater than --message-timeout-secs",
+                )
+                .exit();
+        }
+
+        self.brokers = args.brokers;
+        self.group_id = args.group_id;
+        self.topics = args.topics;
+        self.receive_url = args.receive_url;
+        self.routes_file = args.routes_file;
+        self.routes_reload_interval = Duration::from_secs(args.routes_reload_secs);
+        self.output_topic = args.output_topic;
+        self.jsonl_path = args.jsonl_path;
+        self.jsonl_rotation = Rotation {
+            max_bytes: args.jsonl_max_bytes,
+            max_files: args.jsonl_max_files,
+        };
+        self.stdout = args.stdout;
+        self.unix_socket = args.unix_socket;
+        self.best_effort_sinks = args.best_effort_sinks;
+        self.listen = args.listen;
+        self.admin_listen = args.admin_listen;
+        self.subscription = SubscriptionLimits {
+            history: args.subscribe_history,
+            buffer: args.subscribe_buffer as usize,
+            slow_client: args.slow_client_policy,
+        };
+        self.offset_reset = args.offset_reset;
+        self.start = match (args.start_from, args.start_time) {
+            (Some(OffsetReset::Earliest), _) => StartPosition::Earliest,
+            (Some(OffsetReset::Latest), _) => StartPosition::Latest,
+            (None, Some(time)) => StartPosition::Time(time),
+            (None, None) if !args.start_offset.is_empty() => {
+                StartPosition::Offsets(args.start_offset)
+            }
+            (None, None) => StartPosition::Committed,
+        };
+        self.replay = args.replay_from.map(|from| ReplayWindow {
+            from,
+            to: args.replay_to.unwrap_or_else(Utc::now),
+        });
+        self.session_timeout_ms = args.session_timeout_ms;
+        self.message_timeout = chrono::Duration::seconds(args.message_timeout_secs as i64);
+        // segments of a replayed window are old, send_time deadlines would have passed
+        self.deadline_from = match self.replay {
+            Some(_) => DeadlineFrom::FirstSeen,
+            None => args.deadline_from,
+        };
+        self.cache_ttl = chrono::Duration::seconds(args.cache_ttl_secs as i64);
+        self.housekeeping_interval = Duration::from_secs(args.housekeeping_interval_secs);
+        self.commit_interval = Duration::from_millis(args.commit_interval_ms);
+        self.cache_limits = CacheLimits {
+            max_pending_messages: args.max_pending_messages as usize,
+            max_pending_bytes: args.max_pending_bytes as usize,
+            max_seg_count: args.max_seg_count as usize,
+            eviction_policy: args.eviction_policy,
+        };
+        self.skip_duplicate_check = args.skip_duplicate_check;
+        self.partial_delivery = args.partial_delivery.then_some(PartialDelivery {
+            placeholder: args.gap_placeholder,
+        });
+        self.state_dir = args.state_dir;
+        self.max_concurrent_deliveries = args.max_concurrent_deliveries as usize;
+        self.dead_letter_topic = args.dead_letter_topic;
+        self.send_max_retries = args.send_max_retries;
+        self.retry = RetryPolicy {
+            initial_backoff: Duration::from_millis(args.send_retry_backoff_ms),
+            max_backoff: Duration::from_millis(args.send_max_backoff_ms),
+        };
+        self.ensure_topic = args.ensure_topic.then_some(TopicSpec {
+            partitions: args.topic_partitions,
+            replication_factor: args.topic_replication_factor,
+            retention_ms: args.topic_retention_ms,
+            cleanup_policy: args.topic_cleanup_policy,
+        });
+        self
+    }
+    pub fn guarantee(&self, kind: SinkKind) -> Guarantee {
+        if self.best_effort_sinks.contains(&kind) {
+            Guarantee::BestEffort
+        } else {
+            Guarantee::AtLeastOnce
+        }
+    }
+}
+
+// rfc3339 or unix time in millis
+fn timestamp(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(millis) = s.parse::<i64>() {
+        return DateTime::from_timestamp_millis(millis)
+            .ok_or_else(|| format!("{} is out of range", millis));
+    }
+
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("{} is neither rfc3339 nor unix millis: {}", s, e))
+}
+
+// topic:partition:offset, topic names may have colons
+fn partition_offset(s: &str) -> Result<PartitionOffset, String> {
+    let mut parts = s.rsplitn(3, ':');
+    let (offset, partition, topic) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(offset), Some(partition), Some(topic)) if !topic.is_empty() => {
+            (offset, partition, topic)
+        }
+        _ => return Err(format!("{} is not topic:partition:offset", s)),
+    };
+
+    Ok(PartitionOffset {
+        topic: topic.to_owned(),
+        partition: partition.parse().map_err(|e| format!("partition: {}", e))?,
+        offset: offset.parse().map_err(|e| format!("offset: {}", e))?,
+    })
+}
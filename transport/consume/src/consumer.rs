@@ -1,156 +1,571 @@
-use log::{error, info, trace};
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 use tokio::time::Duration;
 
-use tokio_stream::StreamExt;
-
 use anyhow::{anyhow, Error};
-use itertools::Itertools;
 
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time;
 
-use rdkafka::client::ClientContext;
+use chrono::{DateTime, Utc};
+
 use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 
-use rdkafka::consumer::{BaseConsumer, Consumer, ConsumerContext};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+
 use rdkafka::message::Message as KafkaMessage;
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
 
-use crate::message_builder::{self, MessageBuilder};
+use crate::admin::{AdminCommand, PartitionRef};
+use crate::colocation::ColocationCheck;
+use crate::message_builder::{Assembled, MessageBuilder};
+use crate::offsets::{OffsetTracker, SegmentOffset};
+use crate::rebalance::SegmentConsumerContext;
+use crate::seek::{
+    self, OffsetReset, PartitionKey, ReplayProgress, ReplayWindow, StartPosition, SEEK_TIMEOUT,
+};
+use crate::validation::Violation;
 
-use super::sender::MessageSender;
+use crate::delivery::Delivery;
 
 use common::SegmentWithTime;
 
+const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+// delay after a failed recv, doubled while it keeps failing
+const RECV_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const RECV_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Message {
     pub payload: String,
     pub has_error: bool,
     pub send_time: String,
     pub sender: String,
+    // details of has_error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<MessageError>,
+    // delivered without some segments, see gaps
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gaps: Vec<Gap>,
+}
+
+// Bytes of the original payload a missing segment held, unknown when no full segment arrived.
+// The end of the last segment is always unknown.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Gap {
+    pub seg_num: usize,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    // segments didn't arrive in time
+    MissingSegments,
+    // the same segment came with different payloads
+    ChecksumMismatch,
+    // segments don't form a message
+    DecodeError,
+    // message is older than the cache keeps
+    Expired,
+    // dropped by the cache limits
+    Evicted,
+    // given up on through the admin api, delivered as an error message
+    Flushed,
+    // forgotten through the admin api without delivering anything
+    Dropped,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MessageError {
+    pub kind: ErrorKind,
+    pub reason: String,
+    pub missing_seg_nums: Vec<usize>,
+    pub received: usize,
+    pub expected: usize,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
 }
 
-pub struct SegmentConsumer<T: ClientContext + ConsumerContext> {
-    base: BaseConsumer<T>,
-    topic: Option<String>,
+// Messages are reassembled in memory of the group member that owns the partition,
+// so every segment of a message must be produced to the same partition (see ColocationCheck).
+pub struct SegmentConsumer {
+    base: Arc<StreamConsumer<SegmentConsumerContext>>,
+    context: SegmentConsumerContext,
+    group_id: String,
+    topics: Vec<String>,
+    replay: Option<Replay>,
 }
 
-impl<T: ClientContext + ConsumerContext> SegmentConsumer<T> {
-    pub fn new(context: T, group_id: &str, brokers: &str) -> Self {
+// Assigned range of offsets, consuming stops once every partition reaches its end
+struct Replay {
+    window: ReplayWindow,
+    starts: HashMap<PartitionKey, i64>,
+    end_offsets: HashMap<PartitionKey, i64>,
+}
+
+impl SegmentConsumer {
+    pub fn new(
+        group_id: &str,
+        brokers: &str,
+        session_timeout_ms: u32,
+        offset_reset: OffsetReset,
+    ) -> Self {
+        let context = SegmentConsumerContext::default();
+
         let consumer = ClientConfig::new()
             .set("group.id", group_id)
             .set("bootstrap.servers", brokers)
             .set("enable.partition.eof", "false")
-            .set("session.timeout.ms", "6000")
-            .set("enable.auto.commit", "true")
+            .set("session.timeout.ms", session_timeout_ms.to_string())
+            // offsets are committed by start_consume_and_send after delivery
+            .set("enable.auto.commit", "false")
+            // skip segments of aborted produce transactions
+            .set("isolation.level", "read_committed")
             //.set("statistics.interval.ms", "30000")
-            //.set("auto.offset.reset", "smallest")
+            // where a partition without committed offset starts
+            .set("auto.offset.reset", offset_reset.as_str())
             .set_log_level(RDKafkaLogLevel::Debug)
-            .create_with_context(context)
+            .create_with_context(context.clone())
             .expect("Consumer creation failed");
 
         Self {
-            base: consumer,
-            topic: None,
+            base: Arc::new(consumer),
+            context,
+            group_id: group_id.to_owned(),
+            topics: vec![],
+            replay: None,
+        }
+    }
+
+    // Commits the start position for the group before it joins. Refused while the group
+    // has running members, the commit would move the positions of their partitions.
+    pub fn reset_offsets(&self, topics: &[&str], start: &StartPosition) -> Result<(), Error> {
+        if let StartPosition::Committed = start {
+            return Ok(());
+        }
+
+        let members = seek::group_members(self.base.as_ref(), &self.group_id)?;
+        if members > 0 {
+            return Err(anyhow!(
+                "group {} has {} running members, stop them before moving its offsets",
+                self.group_id,
+                members
+            ));
         }
+
+        let list = seek::start_offsets(self.base.as_ref(), &self.partitions(topics)?, start)?;
+        info!("moving committed offsets to {:?}", list);
+        self.base.commit(&list, CommitMode::Sync)?;
+
+        Ok(())
     }
 
-    pub fn subscribe(&mut self, topic: &str) {
+    // Assigns the partitions from the start of the window without joining the group,
+    // offsets are not committed while replaying
+    pub fn assign_replay(
+        &mut self,
+        topics: &[&str],
+        window: ReplayWindow,
+        tail: chrono::Duration,
+    ) -> Result<(), Error> {
+        let (list, end_offsets) =
+            seek::replay_offsets(self.base.as_ref(), &self.partitions(topics)?, &window, tail)?;
+        info!("replaying {:?} up to {:?}", list, end_offsets);
+
+        self.base.assign(&list)?;
+
+        let starts = list
+            .elements()
+            .iter()
+            .filter_map(|e| match e.offset() {
+                Offset::Offset(offset) => Some(((e.topic().to_owned(), e.partition()), offset)),
+                _ => None,
+            })
+            .collect();
+
+        self.topics = topics.iter().map(|t| t.to_string()).collect();
+        self.replay = Some(Replay {
+            window,
+            starts,
+            end_offsets,
+        });
+
+        Ok(())
+    }
+
+    fn partitions(&self, topics: &[&str]) -> Result<Vec<PartitionKey>, Error> {
+        let mut partitions = vec![];
+        for topic in topics {
+            for partition in self.get_all_partitions(topic, SEEK_TIMEOUT)? {
+                partitions.push((topic.to_string(), partition));
+            }
+        }
+        Ok(partitions)
+    }
+
+    // every assigned partition is consumed up to its end offset, see ReplayProgress
+    fn replay_finished(&self, progress: &mut ReplayProgress) -> bool {
+        match self.base.position() {
+            Ok(position) => {
+                for e in position.elements() {
+                    progress.positioned(&(e.topic().to_owned(), e.partition()), e.offset());
+                }
+            }
+            Err(e) => error!("failed to get replay position: {}", e),
+        }
+
+        progress.finished(|(topic, partition)| {
+            let watermarks = tokio::task::block_in_place(|| {
+                self.base.fetch_watermarks(topic, *partition, SEEK_TIMEOUT)
+            });
+            match watermarks {
+                Ok((_, high)) => Some(high),
+                Err(e) => {
+                    error!("failed to get watermarks of {}:{}: {}", topic, partition, e);
+                    None
+                }
+            }
+        })
+    }
+
+    pub fn subscribe(&mut self, topics: &[&str]) {
         self.base
-            .subscribe(&[topic])
-            .expect("Can't subscribe to specified topic");
+            .subscribe(topics)
+            .expect("Can't subscribe to specified topics");
 
-        self.topic = Some(topic.into());
+        self.topics = topics.iter().map(|t| t.to_string()).collect();
     }
 
+    // Message is sent as soon as its last segment is consumed,
+    // incomplete ones are reported when their deadline passes.
+    // Finished records are cleaned every housekeeping_interval.
+    // Offsets are committed every commit_interval up to the first undelivered message
+    // and before partitions are revoked, see SegmentConsumerContext.
+    // Consuming waits while max_concurrent_deliveries messages are being delivered.
+    // A message an at-least-once sink neither stored nor dead-lettered ends the loop with an error.
+    // Admin commands are handled between segments.
+    // A replay returns once its range is consumed and the messages of its window are delivered.
     pub async fn start_consume_and_send(
         &self,
-        sender: MessageSender,
+        delivery: Delivery,
         message_builder: MessageBuilder,
-        collect_message_interval: Duration,
+        housekeeping_interval: Duration,
+        commit_interval: Duration,
+        max_concurrent_deliveries: usize,
+        mut admin: mpsc::Receiver<AdminCommand>,
     ) -> Result<(), Error> {
-        if self.topic.is_none() {
-            return Err(anyhow!("call subscribe first"));
+        if self.topics.is_empty() {
+            return Err(anyhow!("call subscribe or assign_replay first"));
         }
 
         info!("Started to consume and send");
 
-        // self.base.
-
-        let sender = Arc::new(sender);
+        let delivery = Arc::new(delivery);
+        let offsets = Arc::new(OffsetTracker::new());
         let message_builder = Arc::new(message_builder);
-
-        //let mut interval_stream = IntervalStream::new(interval);
+        self.context.attach(
+            Arc::downgrade(&self.base),
+            offsets.clone(),
+            message_builder.clone(),
+        );
+        let delivery_slots = Arc::new(Semaphore::new(max_concurrent_deliveries));
+        let (undelivered_tx, mut undelivered) = mpsc::unbounded_channel::<Error>();
+
+        let mut colocation = ColocationCheck::new();
+        let mut housekeeping = tokio::time::interval(housekeeping_interval);
+        let mut commit = tokio::time::interval(commit_interval);
+        let mut metrics_log = tokio::time::interval(METRICS_LOG_INTERVAL);
+        let mut replay_progress = self
+            .replay
+            .as_ref()
+            .map(|r| ReplayProgress::new(r.starts.clone(), r.end_offsets.clone()));
+        let mut replay_consumed = false;
+        let mut recv_backoff = RECV_RETRY_BACKOFF;
 
         loop {
-            let mut segments: Vec<SegmentWithTime> = vec![];
+            if replay_consumed && !message_builder.has_pending() {
+                break;
+            }
 
-            let start_time = time::Instant::now();
+            let next_deadline = message_builder.next_deadline();
 
-            loop {
-                if start_time.elapsed() > collect_message_interval {
-                    trace!("Stopping loop after: {:?}", start_time.elapsed());
-                    break;
-                }
-
-                // Detach can be done before moving to another thread and building segment there to perf upupup
-                let res = match self.base.poll(Duration::from_millis(500)) {
-                    Some(mess) => match mess {
-                        Ok(mess) => mess.detach(),
+            let messages = tokio::select! {
+                res = self.base.recv() => {
+                    let res = match res {
+                        Ok(mess) => {
+                            recv_backoff = RECV_RETRY_BACKOFF;
+                            mess.detach()
+                        }
                         Err(e) => {
-                            error!("{}", e);
+                            if let Some((code, reason)) = self.base.client().fatal_error() {
+                                return Err(anyhow!("fatal consumer error {:?}: {}", code, reason));
+                            }
+                            if is_fatal(&e) {
+                                return Err(anyhow!("consumer error: {}", e));
+                            }
+                            error!("failed to consume, retrying in {:?}: {}", recv_backoff, e);
+                            tokio::time::sleep(recv_backoff).await;
+                            recv_backoff = (recv_backoff * 2).min(RECV_MAX_BACKOFF);
+                            continue;
+                        }
+                    };
+
+                    info!("got message: {:?}", &res);
+
+                    let (topic, partition, offset) =
+                        (res.topic().to_owned(), res.partition(), res.offset());
+
+                    // segments produced after the end belong to later messages
+                    if let Some(progress) = &mut replay_progress {
+                        let key = (topic.clone(), partition);
+                        progress.consumed(&key, offset);
+                        match progress.end(&key) {
+                            Some(end) if offset < end => {}
+                            _ => continue,
+                        }
+                    }
+                    let segment = match SegmentWithTime::try_from(res)
+                        .map_err(|e| (None, e.to_string()))
+                        .and_then(|s| match message_builder.check(&s) {
+                            Ok(_) => Ok(s),
+                            Err(reason) => Err((Some(s), reason)),
+                        }) {
+                        Ok(segment) => segment,
+                        Err((segment, reason)) => {
+                            message_builder.quarantine(Violation {
+                                topic: topic.clone(),
+                                partition,
+                                offset,
+                                sender: segment.as_ref().map(|s| s.segment.sender.clone()),
+                                send_time: segment.map(|s| s.send_time),
+                                reason,
+                                quarantined_at: Utc::now(),
+                            });
+                            offsets.skip(&topic, partition, offset);
                             continue;
                         }
-                    },
-                    None => {
+                    };
+                    let key = (segment.segment.sender.clone(), segment.send_time.clone());
+
+                    if !colocation.check(&topic, &segment.segment.sender, &segment.send_time, partition) {
+                        message_builder.misplaced();
+                    }
+
+                    let segment_offset = offsets.track(&key, &topic, partition, offset);
+
+                    match message_builder.add_segment(segment) {
+                        Assembled::Complete(mes) | Assembled::Rejected(mes) => vec![mes],
+                        Assembled::Pending => vec![],
+                        Assembled::Duplicate => {
+                            offsets.release(&[segment_offset]);
+                            vec![]
+                        }
+                        Assembled::Skipped => {
+                            offsets.release(&offsets.take(&key));
+                            vec![]
+                        }
+                    }
+                }
+                _ = sleep_until(next_deadline) => message_builder.expire(),
+                Some(e) = undelivered.recv() => {
+                    // the offsets of the message stay pending, the next run consumes it again
+                    if let Some(list) = offsets.to_commit() {
+                        if let Err(e) = self.base.commit(&list, CommitMode::Sync) {
+                            error!("failed to commit offsets: {}", e);
+                        }
+                    }
+                    return Err(e.context("message is not delivered, consuming stopped"));
+                }
+                Some(command) = admin.recv() => {
+                    self.handle_admin(command, &message_builder, &offsets)
+                }
+                _ = housekeeping.tick() => {
+                    message_builder.clean();
+                    continue;
+                }
+                _ = metrics_log.tick() => {
+                    info!(
+                        "metrics: {:?}, messages dropped by best-effort sinks: {}",
+                        message_builder.metrics(),
+                        delivery.dropped()
+                    );
+                    continue;
+                }
+                _ = commit.tick() => {
+                    message_builder.sync_state();
+                    if let Some(progress) = &mut replay_progress {
+                        replay_consumed = self.replay_finished(progress);
                         continue;
                     }
-                };
+                    if let Some(list) = offsets.to_commit() {
+                        if let Err(e) = self.base.commit(&list, CommitMode::Async) {
+                            error!("failed to commit offsets: {}", e);
+                        }
+                    }
+                    continue;
+                }
+            };
 
-                info!("got message: {:?}", &res);
+            for mes in messages {
+                let segment_offsets = offsets.take(&(mes.sender.clone(), mes.send_time.clone()));
 
-                let segment = res.into();
+                if let Some(replay) = &self.replay {
+                    if !replay.window.contains(&mes.send_time) {
+                        continue;
+                    }
+                }
 
-                segments.push(segment);
+                let slot = delivery_slots.clone().acquire_owned().await?;
+
+                tokio::spawn(deliver(
+                    delivery.clone(),
+                    offsets.clone(),
+                    mes,
+                    segment_offsets,
+                    undelivered_tx.clone(),
+                    slot,
+                ));
             }
+        }
 
-            //if !segments.is_empty() {
-            let sender = sender.clone();
-            let message_builder = message_builder.clone();
+        // every slot is free once the last delivery is done
+        let _ = delivery_slots
+            .acquire_many(max_concurrent_deliveries as u32)
+            .await?;
+        info!("replay finished");
 
-            tokio::spawn(async move {
-                let messages =
-                    tokio::task::spawn_blocking(move || message_builder.build_messages(segments))
-                        .await
-                        .unwrap();
+        Ok(())
+    }
 
-                for mes in messages {
-                    sender.send_message(&mes).await.unwrap();
+    // Returns the flushed message to deliver
+    fn handle_admin(
+        &self,
+        command: AdminCommand,
+        message_builder: &MessageBuilder,
+        offsets: &OffsetTracker,
+    ) -> Vec<Message> {
+        match command {
+            AdminCommand::Pending(reply) => {
+                let _ = reply.send(message_builder.pending_entries());
+            }
+            AdminCommand::Entry(m, reply) => {
+                let _ = reply.send(message_builder.pending_entry(&m.sender, &m.send_time));
+            }
+            AdminCommand::Flush(m, reply) => {
+                let flushed = message_builder.flush(&m.sender, &m.send_time);
+                let _ = reply.send(flushed.is_some());
+                return flushed.into_iter().collect();
+            }
+            AdminCommand::Drop(m, reply) => {
+                let dropped = message_builder.drop_pending(&m.sender, &m.send_time);
+                if let Some(key) = &dropped {
+                    offsets.release(&offsets.take(key));
                 }
-            });
-            //}
+                let _ = reply.send(dropped.is_some());
+            }
+            AdminCommand::Pause(partitions, reply) => {
+                info!("pausing {:?}", partitions);
+                let _ = reply.send(
+                    partition_list(&partitions)
+                        .and_then(|list| self.base.pause(&list).map_err(|e| e.to_string())),
+                );
+            }
+            AdminCommand::Resume(partitions, reply) => {
+                info!("resuming {:?}", partitions);
+                let _ = reply.send(
+                    partition_list(&partitions)
+                        .and_then(|list| self.base.resume(&list).map_err(|e| e.to_string())),
+                );
+            }
+            AdminCommand::Quarantine(reply) => {
+                let _ = reply.send(message_builder.quarantined());
+            }
         }
 
-        Ok(())
+        vec![]
     }
 
-    pub fn consumer(&self) -> &BaseConsumer<T> {
-        &self.base
-    }
+    pub fn get_all_partitions(
+        &self,
+        topic: &str,
+        fetch_timeout: Duration,
+    ) -> Result<Vec<i32>, Error> {
+        let metadata = self.base.fetch_metadata(Some(topic), fetch_timeout)?;
 
-    pub fn get_all_partitions(&self, topic: &str, fetch_timeout: Duration) -> Vec<i32> {
-        self.base
-            .fetch_metadata(Some(topic), fetch_timeout)
-            .unwrap()
+        let partitions = metadata
             .topics()
             .iter()
             .find(|t| t.name() == topic)
-            .unwrap()
+            .ok_or_else(|| anyhow!("topic {} not found in metadata", topic))?
             .partitions()
             .iter()
             .map(|p| p.id())
-            .collect()
+            .collect();
+
+        Ok(partitions)
+    }
+}
+
+// only assigned partitions can be paused, a rebalance resumes them
+fn partition_list(partitions: &[PartitionRef]) -> Result<TopicPartitionList, String> {
+    if partitions.is_empty() {
+        return Err("no partitions given".to_owned());
+    }
+
+    let mut list = TopicPartitionList::new();
+    for p in partitions {
+        list.add_partition(&p.topic, p.partition);
+    }
+    Ok(list)
+}
+
+async fn sleep_until(deadline: Option<DateTime<Utc>>) {
+    match deadline {
+        Some(deadline) => {
+            let wait = (deadline - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// errors retrying recv won't fix
+fn is_fatal(e: &KafkaError) -> bool {
+    matches!(
+        e.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::Fatal
+                | RDKafkaErrorCode::Authentication
+                | RDKafkaErrorCode::SaslAuthenticationFailed
+                | RDKafkaErrorCode::TopicAuthorizationFailed
+                | RDKafkaErrorCode::GroupAuthorizationFailed
+                | RDKafkaErrorCode::ClusterAuthorizationFailed
+        )
+    )
+}
+
+// Offsets of the message segments stay uncommitted until it is delivered or dead-lettered,
+// an undelivered message stops the consume loop
+async fn deliver(
+    delivery: Arc<Delivery>,
+    offsets: Arc<OffsetTracker>,
+    mes: Message,
+    segment_offsets: Vec<SegmentOffset>,
+    undelivered: mpsc::UnboundedSender<Error>,
+    _slot: OwnedSemaphorePermit,
+) {
+    match delivery.deliver(&mes).await {
+        Ok(_) => offsets.release(&segment_offsets),
+        Err(e) => {
+            let _ = undelivered.send(e);
+        }
     }
 }
 
@@ -0,0 +1,406 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use futures::future::join_all;
+use log::{error, warn};
+
+use rdkafka::config::ClientConfig;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use crate::consumer::Message;
+use crate::sink::{Guarantee, MessageSink, SendError};
+
+const DEAD_LETTER_RETRY_DELAY: Duration = Duration::from_secs(5);
+// the message is left undelivered after about a minute, consuming stops
+const DEAD_LETTER_MAX_ATTEMPTS: u32 = 12;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+pub struct DeliveryFailure {
+    pub error: SendError,
+    pub attempts: u32,
+}
+
+// Assembled messages a sink didn't accept, with the failure in headers
+pub struct DeadLetterProducer {
+    base: FutureProducer,
+    topic: String,
+}
+
+impl DeadLetterProducer {
+    pub fn new(brokers: &str, topic: &str) -> Self {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("Dead-letter producer creation error");
+
+        Self {
+            base: producer,
+            topic: topic.to_owned(),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub async fn store(
+        &self,
+        message: &Message,
+        failure: &DeliveryFailure,
+        sink: &dyn MessageSink,
+    ) -> Result<()> {
+        let payload = serde_json::to_vec(message)?;
+
+        let status = failure
+            .error
+            .status
+            .map(|s| s.as_u16().to_string())
+            .unwrap_or_default();
+
+        let headers = OwnedHeaders::new()
+            .add("failure_reason", &failure.error.reason.to_string())
+            .add("failure_status", &status)
+            .add("attempts", &failure.attempts.to_string())
+            .add("failed_at", &Utc::now().to_rfc3339())
+            .add("sink", sink.kind().as_str())
+            .add("sink_target", sink.target());
+
+        let record = FutureRecord::to(&self.topic)
+            .payload(&payload)
+            .key(&message.send_time)
+            .headers(headers);
+
+        self.base
+            .send(record, Duration::from_secs(0))
+            .await
+            .map_err(|e| anyhow!(e.0))?;
+
+        Ok(())
+    }
+}
+
+struct Output {
+    sink: Box<dyn MessageSink>,
+    guarantee: Guarantee,
+}
+
+// Sends the message to every sink, falling back to the dead-letter topic
+// for the at-least-once ones. Without the topic their failures are retried
+// until success, a permanent one leaves the message undelivered.
+pub struct Delivery {
+    outputs: Vec<Output>,
+    dead_letter: Option<DeadLetterProducer>,
+    retry: RetryPolicy,
+    max_retries: u32,
+    dropped: AtomicUsize,
+}
+
+impl Delivery {
+    pub fn new(
+        dead_letter: Option<DeadLetterProducer>,
+        retry: RetryPolicy,
+        max_retries: u32,
+    ) -> Self {
+        Self {
+            outputs: vec![],
+            dead_letter,
+            retry,
+            max_retries,
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn sink(mut self, sink: Box<dyn MessageSink>, guarantee: Guarantee) -> Self {
+        if guarantee == Guarantee::AtLeastOnce && self.dead_letter.is_none() {
+            warn!(
+                "no dead-letter topic, failures of {} sink {} are retried until it accepts the message, a permanent one stops consuming",
+                sink.kind().as_str(),
+                sink.target()
+            );
+        }
+        self.outputs.push(Output { sink, guarantee });
+        self
+    }
+
+    // Messages best-effort sinks failed
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Returns once every sink stored, dead-lettered or dropped the message,
+    // an error means an at-least-once sink has neither stored nor dead-lettered it.
+    // Sinks are independent, a slow or failing one doesn't hold the others.
+    pub async fn deliver(&self, message: &Message) -> Result<()> {
+        join_all(
+            self.outputs
+                .iter()
+                .map(|output| self.deliver_to(output, message)),
+        )
+        .await
+        .into_iter()
+        .collect()
+    }
+
+    async fn deliver_to(&self, output: &Output, message: &Message) -> Result<()> {
+        let sink = output.sink.as_ref();
+
+        if output.guarantee == Guarantee::BestEffort {
+            if let Err(e) = sink.send(message).await {
+                warn!(
+                    "{} sink {} failed message {}/{}, dropping it: {}",
+                    sink.kind().as_str(),
+                    sink.target(),
+                    message.sender,
+                    message.send_time,
+                    e
+                );
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            return Ok(());
+        }
+
+        // without a dead-letter topic retryable failures are retried until success
+        let max_retries = self.dead_letter.as_ref().map(|_| self.max_retries);
+
+        let failure = match self.send_with_retry(sink, message, max_retries).await {
+            Ok(_) => return Ok(()),
+            Err(failure) => failure,
+        };
+        sink.forget(message);
+
+        let dead_letter = match &self.dead_letter {
+            Some(dead_letter) => dead_letter,
+            None => {
+                return Err(anyhow!(
+                    "{} sink {} rejected message {}/{}: {}",
+                    sink.kind().as_str(),
+                    sink.target(),
+                    message.sender,
+                    message.send_time,
+                    failure.error
+                ))
+            }
+        };
+
+        warn!(
+            "failed to deliver message {}/{} to {} sink {} after {} attempts, sending to {}: {}",
+            message.sender,
+            message.send_time,
+            sink.kind().as_str(),
+            sink.target(),
+            failure.attempts,
+            dead_letter.topic(),
+            failure.error
+        );
+
+        let mut attempts = 0;
+        loop {
+            let e = match dead_letter.store(message, &failure, sink).await {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+
+            attempts += 1;
+            if attempts >= DEAD_LETTER_MAX_ATTEMPTS {
+                return Err(anyhow!(
+                    "failed to store message {}/{} in {} after {} attempts: {}",
+                    message.sender,
+                    message.send_time,
+                    dead_letter.topic(),
+                    attempts,
+                    e
+                ));
+            }
+            error!(
+                "failed to store message {}/{} in {}, retrying in {:?}: {}",
+                message.sender,
+                message.send_time,
+                dead_letter.topic(),
+                DEAD_LETTER_RETRY_DELAY,
+                e
+            );
+            tokio::time::sleep(DEAD_LETTER_RETRY_DELAY).await;
+        }
+    }
+
+    // Retries with exponential backoff, max_retries None retries until success
+    async fn send_with_retry(
+        &self,
+        sink: &dyn MessageSink,
+        message: &Message,
+        max_retries: Option<u32>,
+    ) -> Result<(), DeliveryFailure> {
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let error = match sink.send(message).await {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+
+            let exhausted = match max_retries {
+                Some(max_retries) => attempts > max_retries,
+                None => false,
+            };
+            if !error.retryable || exhausted {
+                return Err(DeliveryFailure { error, attempts });
+            }
+
+            warn!(
+                "failed to send message {}/{} to {} sink {} (attempt {}), retrying in {:?}: {}",
+                message.sender,
+                message.send_time,
+                sink.kind().as_str(),
+                sink.target(),
+                attempts,
+                backoff,
+                error
+            );
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.retry.max_backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::sink::SinkKind;
+
+    // answers with the given errors in order, then accepts
+    struct FailingSink {
+        errors: Mutex<Vec<SendError>>,
+        sends: AtomicUsize,
+    }
+
+    impl FailingSink {
+        fn new(mut errors: Vec<SendError>) -> Self {
+            errors.reverse();
+            Self {
+                errors: Mutex::new(errors),
+                sends: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl MessageSink for FailingSink {
+        fn kind(&self) -> SinkKind {
+            SinkKind::Http
+        }
+
+        fn target(&self) -> &str {
+            "test"
+        }
+
+        async fn send(&self, _message: &Message) -> Result<(), SendError> {
+            self.sends.fetch_add(1, Ordering::Relaxed);
+            match self.errors.lock().unwrap().pop() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn delivery() -> Delivery {
+        let retry = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        Delivery::new(None, retry, 2)
+    }
+
+    fn message() -> Message {
+        Message {
+            payload: "message".to_owned(),
+            has_error: false,
+            sender: "sender".to_owned(),
+            send_time: "1700000000000".to_owned(),
+            error: None,
+            partial: false,
+            gaps: vec![],
+        }
+    }
+
+    fn retryable() -> SendError {
+        SendError::retryable(anyhow!("unavailable"))
+    }
+
+    #[tokio::test]
+    async fn retryable_failures_are_retried_up_to_max_retries() {
+        let sink = FailingSink::new(vec![retryable(), retryable(), retryable(), retryable()]);
+
+        let failure = delivery()
+            .send_with_retry(&sink, &message(), Some(2))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(failure.attempts, 3);
+        assert!(failure.error.retryable);
+    }
+
+    #[tokio::test]
+    async fn permanent_failure_is_not_retried() {
+        let sink = FailingSink::new(vec![SendError::permanent(anyhow!("bad request"))]);
+
+        let failure = delivery()
+            .send_with_retry(&sink, &message(), Some(2))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(failure.attempts, 1);
+        assert!(!failure.error.retryable);
+    }
+
+    #[tokio::test]
+    async fn without_dead_letter_topic_retryable_failures_are_retried_until_success() {
+        let sink = Arc::new(FailingSink::new(vec![
+            retryable(),
+            retryable(),
+            retryable(),
+            retryable(),
+        ]));
+        let delivery = delivery().sink(Box::new(sink.clone()), Guarantee::AtLeastOnce);
+
+        assert!(delivery.deliver(&message()).await.is_ok());
+        assert_eq!(sink.sends.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn without_dead_letter_topic_permanent_failure_leaves_message_undelivered() {
+        let sink = FailingSink::new(vec![SendError::permanent(anyhow!("bad request"))]);
+        let delivery = delivery().sink(Box::new(sink), Guarantee::AtLeastOnce);
+
+        assert!(delivery.deliver(&message()).await.is_err());
+        assert_eq!(delivery.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn best_effort_failure_is_dropped() {
+        let sink = Arc::new(FailingSink::new(vec![retryable()]));
+        let delivery = delivery().sink(Box::new(sink.clone()), Guarantee::BestEffort);
+
+        assert!(delivery.deliver(&message()).await.is_ok());
+        assert_eq!(sink.sends.load(Ordering::Relaxed), 1);
+        assert_eq!(delivery.dropped(), 1);
+    }
+}
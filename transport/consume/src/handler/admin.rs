@@ -0,0 +1,119 @@
+use warp::http::StatusCode;
+use warp::reply::{json, with_status, Reply};
+
+use crate::admin::{Admin, AdminCommand, MessageRef, PartitionRef};
+
+fn unavailable() -> Box<dyn Reply> {
+    Box::new(with_status(
+        "consume loop is not running",
+        StatusCode::SERVICE_UNAVAILABLE,
+    ))
+}
+
+fn not_found(message: &MessageRef) -> Box<dyn Reply> {
+    Box::new(with_status(
+        format!(
+            "no pending message {}/{}",
+            message.sender, message.send_time
+        ),
+        StatusCode::NOT_FOUND,
+    ))
+}
+
+pub async fn list_pending(admin: Admin) -> Result<Box<dyn Reply>, warp::Rejection> {
+    Ok(match admin.request(AdminCommand::Pending).await {
+        Some(entries) => Box::new(json(&entries)),
+        None => unavailable(),
+    })
+}
+
+pub async fn get_pending(
+    message: MessageRef,
+    admin: Admin,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let request = message.clone();
+
+    Ok(
+        match admin
+            .request(|reply| AdminCommand::Entry(request, reply))
+            .await
+        {
+            Some(Some(entry)) => Box::new(json(&entry)),
+            Some(None) => not_found(&message),
+            None => unavailable(),
+        },
+    )
+}
+
+pub async fn flush_pending(
+    message: MessageRef,
+    admin: Admin,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let request = message.clone();
+
+    Ok(
+        match admin
+            .request(|reply| AdminCommand::Flush(request, reply))
+            .await
+        {
+            Some(true) => Box::new(StatusCode::ACCEPTED),
+            Some(false) => not_found(&message),
+            None => unavailable(),
+        },
+    )
+}
+
+pub async fn drop_pending(
+    message: MessageRef,
+    admin: Admin,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let request = message.clone();
+
+    Ok(
+        match admin
+            .request(|reply| AdminCommand::Drop(request, reply))
+            .await
+        {
+            Some(true) => Box::new(StatusCode::NO_CONTENT),
+            Some(false) => not_found(&message),
+            None => unavailable(),
+        },
+    )
+}
+
+pub async fn pause_partitions(
+    partitions: Vec<PartitionRef>,
+    admin: Admin,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    partitions_reply(
+        admin
+            .request(|reply| AdminCommand::Pause(partitions, reply))
+            .await,
+    )
+}
+
+pub async fn resume_partitions(
+    partitions: Vec<PartitionRef>,
+    admin: Admin,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    partitions_reply(
+        admin
+            .request(|reply| AdminCommand::Resume(partitions, reply))
+            .await,
+    )
+}
+
+fn partitions_reply(result: Option<Result<(), String>>) -> Result<Box<dyn Reply>, warp::Rejection> {
+    Ok(match result {
+        Some(Ok(_)) => Box::new(StatusCode::NO_CONTENT),
+        Some(Err(e)) => Box::new(with_status(e, StatusCode::BAD_REQUEST)),
+        None => unavailable(),
+    })
+}
+
+pub async fn list_quarantine(admin: Admin) -> Result<Box<dyn Reply>, warp::Rejection> {
+    Ok(match admin.request(AdminCommand::Quarantine).await {
+        Some(violations) => Box::new(json(&violations)),
+        None => unavailable(),
+    })
+}
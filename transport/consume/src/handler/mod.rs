@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use admin::{
+    drop_pending, flush_pending, get_pending, list_pending, list_quarantine, pause_partitions,
+    resume_partitions,
+};
+use subscribe::{subscribe_sse, subscribe_ws, SubscribeQuery};
+use warp::{filters::BoxedFilter, Filter};
+
+use crate::admin::{Admin, MessageRef};
+use crate::subscription::SubscriptionHub;
+
+mod admin;
+mod subscribe;
+
+fn hub_filter(hub: Arc<SubscriptionHub>) -> BoxedFilter<(Arc<SubscriptionHub>,)> {
+    warp::any().map(move || hub.clone()).boxed()
+}
+
+fn admin_filter(admin: Admin) -> BoxedFilter<(Admin,)> {
+    warp::any().map(move || admin.clone()).boxed()
+}
+
+// pending message by ?sender=..&send_time=.., senders may have characters paths can't
+fn message_filter() -> BoxedFilter<(MessageRef,)> {
+    warp::query::<MessageRef>().boxed()
+}
+
+// Served on --listen, open to the subscribers
+pub fn subscribe_routes(
+    hub: Arc<SubscriptionHub>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // websocket when the client asks for an upgrade, server-sent events otherwise
+    let subscribe_ws = warp::get()
+        .and(warp::path("subscribe"))
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::query::<SubscribeQuery>())
+        .and(hub_filter(hub.clone()))
+        .and_then(subscribe_ws);
+
+    let subscribe_sse = warp::get()
+        .and(warp::path("subscribe"))
+        .and(warp::path::end())
+        .and(warp::query::<SubscribeQuery>())
+        .and(warp::header::optional::<String>("last-event-id"))
+        .and(hub_filter(hub))
+        .and_then(subscribe_sse);
+
+    subscribe_ws.or(subscribe_sse)
+}
+
+// Served on --admin-listen, the api changes what is delivered so it isn't exposed to subscribers
+pub fn admin_routes(
+    admin: Admin,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let pending = warp::get()
+        .and(warp::path!("admin" / "pending"))
+        .and(admin_filter(admin.clone()))
+        .and_then(list_pending);
+
+    let pending_entry = warp::get()
+        .and(warp::path!("admin" / "pending" / "entry"))
+        .and(message_filter())
+        .and(admin_filter(admin.clone()))
+        .and_then(get_pending);
+
+    let flush = warp::post()
+        .and(warp::path!("admin" / "pending" / "entry" / "flush"))
+        .and(message_filter())
+        .and(admin_filter(admin.clone()))
+        .and_then(flush_pending);
+
+    let drop = warp::delete()
+        .and(warp::path!("admin" / "pending" / "entry"))
+        .and(message_filter())
+        .and(admin_filter(admin.clone()))
+        .and_then(drop_pending);
+
+    let pause = warp::post()
+        .and(warp::path!("admin" / "partitions" / "pause"))
+        .and(warp::body::json())
+        .and(admin_filter(admin.clone()))
+        .and_then(pause_partitions);
+
+    let resume = warp::post()
+        .and(warp::path!("admin" / "partitions" / "resume"))
+        .and(warp::body::json())
+        .and(admin_filter(admin.clone()))
+        .and_then(resume_partitions);
+
+    let quarantine = warp::get()
+        .and(warp::path!("admin" / "quarantine"))
+        .and(admin_filter(admin))
+        .and_then(list_quarantine);
+
+    pending
+        .or(pending_entry)
+        .or(flush)
+        .or(drop)
+        .or(pause)
+        .or(resume)
+        .or(quarantine)
+}
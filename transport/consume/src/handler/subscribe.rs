@@ -0,0 +1,125 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use futures::{SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use warp::http::StatusCode;
+use warp::reply::{with_status, Reply};
+use warp::ws::{Message as WsMessage, WebSocket, Ws};
+
+use common::pattern::Pattern;
+
+use crate::subscription::{Cursor, Event, SubscriptionHub};
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribeQuery {
+    // glob of the senders to stream, all of them without it
+    sender: Option<String>,
+    // resume after this message, only live messages without it
+    cursor: Option<String>,
+}
+
+impl SubscribeQuery {
+    // Last-Event-ID of a reconnecting event source wins over the cursor of its url
+    fn parse(
+        self,
+        last_event_id: Option<String>,
+    ) -> Result<(Option<Pattern>, Option<Cursor>), String> {
+        let sender = self
+            .sender
+            .map(|glob| Pattern::glob(&glob))
+            .transpose()
+            .map_err(|e| format!("sender: {}", e))?;
+        let cursor = last_event_id
+            .or(self.cursor)
+            .map(|c| c.parse::<Cursor>())
+            .transpose()?;
+
+        Ok((sender, cursor))
+    }
+}
+
+pub async fn subscribe_ws(
+    ws: Ws,
+    query: SubscribeQuery,
+    hub: Arc<SubscriptionHub>,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let (sender, cursor) = match query.parse(None) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(Box::new(with_status(e, StatusCode::BAD_REQUEST))),
+    };
+
+    let events = hub.subscribe(sender, cursor);
+
+    Ok(Box::new(
+        ws.on_upgrade(move |socket| stream_ws(socket, events)),
+    ))
+}
+
+pub async fn subscribe_sse(
+    query: SubscribeQuery,
+    last_event_id: Option<String>,
+    hub: Arc<SubscriptionHub>,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let (sender, cursor) = match query.parse(last_event_id) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(Box::new(with_status(e, StatusCode::BAD_REQUEST))),
+    };
+
+    let events = hub
+        .subscribe(sender, cursor)
+        .map(|event| Ok::<_, Infallible>(sse_event(event)));
+
+    Ok(Box::new(warp::sse::reply(
+        warp::sse::keep_alive().stream(events),
+    )))
+}
+
+// Ends when the client closes the socket or the hub drops a slow client
+async fn stream_ws(socket: WebSocket, events: impl Stream<Item = Event>) {
+    let (mut tx, mut rx) = socket.split();
+    tokio::pin!(events);
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let event = match event {
+                    Some(event) => event,
+                    None => break,
+                };
+                if tx.send(WsMessage::text(ws_frame(&event))).await.is_err() {
+                    return;
+                }
+            }
+            incoming = rx.next() => match incoming {
+                Some(Ok(m)) if !m.is_close() => continue,
+                _ => return,
+            }
+        }
+    }
+
+    let _ = tx.close().await;
+}
+
+// {"cursor": "...", "message": {...}} or {"lost": {"from": 3, "to": 7}}
+fn ws_frame(event: &Event) -> String {
+    match event {
+        Event::Message { cursor, json } => {
+            format!(r#"{{"cursor":"{}","message":{}}}"#, cursor, json)
+        }
+        Event::Lost { from, to } => format!(r#"{{"lost":{{"from":{},"to":{}}}}}"#, from, to),
+    }
+}
+
+// the cursor is the event id, so event sources resume by themselves
+fn sse_event(event: Event) -> warp::sse::Event {
+    match event {
+        Event::Message { cursor, json } => warp::sse::Event::default()
+            .id(cursor.to_string())
+            .event("message")
+            .data(json.as_str()),
+        Event::Lost { from, to } => warp::sse::Event::default()
+            .event("lost")
+            .data(format!(r#"{{"from":{},"to":{}}}"#, from, to)),
+    }
+}
@@ -1,72 +1,157 @@
-use log::info;
+use std::{net::SocketAddr, sync::Arc};
 
-use chrono::{DateTime, Duration, NaiveDate, Utc};
+use log::{error, info};
 
-use rdkafka::client::ClientContext;
-use rdkafka::consumer::{ConsumerContext, Rebalance};
-use rdkafka::error::KafkaResult;
-use rdkafka::topic_partition_list::TopicPartitionList;
 use rdkafka::util::get_rdkafka_version;
 
-use common::setup_env_logger;
+use common::{setup_env_logger, topic::ensure_topics};
 
-mod command;
+mod admin;
+mod colocation;
+mod config;
 mod consumer;
+mod delivery;
+mod handler;
 mod message_builder;
-mod sender;
-
-use command::Config;
+mod metrics;
+mod offsets;
+mod rebalance;
+mod seek;
+mod sink;
+mod state;
+mod subscription;
+mod validation;
+
+use admin::Admin;
+use config::Config;
 use consumer::SegmentConsumer;
-use sender::MessageSender;
+use delivery::{DeadLetterProducer, Delivery};
+use handler::{admin_routes, subscribe_routes};
+use sink::{HttpSink, JsonlSink, KafkaSink, RoutedHttpSink, SinkKind, StdoutSink, UnixSocketSink};
+use state::StateStore;
+use subscription::SubscriptionHub;
 
 use crate::message_builder::MessageBuilder;
 
-// A context can be used to change the behavior of producers and consumers by adding callbacks
-// that will be executed by librdkafka.
-// This particular context sets up custom callbacks to log rebalancing events.
-
-// A type alias with your custom consumer can be created for convenience.
-// type LoggingConsumer = BaseConsumer<CustomContext>;
-
-pub struct SegmentConsumerContext;
-
-impl ClientContext for SegmentConsumerContext {}
-
-impl ConsumerContext for SegmentConsumerContext {
-    fn pre_rebalance(&self, rebalance: &Rebalance) {
-        info!("Pre rebalance {:?}", rebalance);
-    }
-
-    fn post_rebalance(&self, rebalance: &Rebalance) {
-        info!("Post rebalance {:?}", rebalance);
-    }
-
-    fn commit_callback(&self, result: KafkaResult<()>, _offsets: &TopicPartitionList) {
-        info!("Committing offsets: {:?}", result);
-    }
-}
+// admin requests waiting for the consume loop
+const ADMIN_QUEUE_CAPACITY: usize = 16;
 
 #[tokio::main]
 async fn main() {
-    let config = Config::from_cmd();
+    let config = Config::build().cmd_parse();
     setup_env_logger(true, "RUST_LOG");
 
+    info!("config: {:?}", config);
+
     let (version_n, version_s) = get_rdkafka_version();
     info!("rd_kafka_version: 0x{:08x}, {}", version_n, version_s);
 
-    let message_sender = MessageSender::new(config.receive_url).unwrap();
-    let message_builder = MessageBuilder::new(Duration::minutes(30), 3);
+    let topics: Vec<&str> = config.topics.iter().map(String::as_str).collect();
 
-    let mut consumer =
-        SegmentConsumer::new(SegmentConsumerContext, &config.group_id, &config.brokers);
+    if let Some(spec) = &config.ensure_topic {
+        let mut provisioned = topics.clone();
+        provisioned.extend(config.output_topic.as_deref());
+        provisioned.extend(config.dead_letter_topic.as_deref());
 
-    consumer.subscribe(&config.topic);
+        ensure_topics(&config.brokers, &provisioned, spec)
+            .await
+            .expect("Topic provisioning error");
+    }
+
+    let dead_letter = config
+        .dead_letter_topic
+        .as_deref()
+        .map(|topic| DeadLetterProducer::new(&config.brokers, topic));
+    let mut delivery = Delivery::new(dead_letter, config.retry.clone(), config.send_max_retries);
+    if let Some(receive_url) = &config.receive_url {
+        let sink = HttpSink::new(receive_url).expect("Receive url error");
+        delivery = delivery.sink(Box::new(sink), config.guarantee(SinkKind::Http));
+    }
+    if let Some(path) = &config.routes_file {
+        let sink =
+            RoutedHttpSink::new(path, config.routes_reload_interval).expect("Routes file error");
+        delivery = delivery.sink(Box::new(sink), config.guarantee(SinkKind::Routed));
+    }
+    if let Some(topic) = &config.output_topic {
+        let sink = KafkaSink::new(&config.brokers, topic);
+        delivery = delivery.sink(Box::new(sink), config.guarantee(SinkKind::Kafka));
+    }
+    if let Some(path) = &config.jsonl_path {
+        let sink =
+            JsonlSink::new(path, config.jsonl_rotation.clone()).expect("Jsonl file opening error");
+        delivery = delivery.sink(Box::new(sink), config.guarantee(SinkKind::Jsonl));
+    }
+    if config.stdout {
+        delivery = delivery.sink(Box::new(StdoutSink), config.guarantee(SinkKind::Stdout));
+    }
+    if let Some(path) = &config.unix_socket {
+        let sink = UnixSocketSink::new(path);
+        delivery = delivery.sink(Box::new(sink), config.guarantee(SinkKind::Unix));
+    }
+    let (admin, admin_commands) = Admin::channel(ADMIN_QUEUE_CAPACITY);
+    if let Some(listen) = &config.listen {
+        let hub = Arc::new(SubscriptionHub::new(config.subscription.clone()));
+        delivery = delivery.sink(Box::new(hub.clone()), config.guarantee(SinkKind::Subscribe));
+
+        let addr = listen.parse::<SocketAddr>().unwrap();
+        info!("Listening on {}", addr);
+        tokio::spawn(warp::serve(subscribe_routes(hub)).run(addr));
+    }
 
-    let _ = consumer
+    let admin_addr = config.admin_listen.parse::<SocketAddr>().unwrap();
+    let (admin_addr, admin_server) = warp::serve(admin_routes(admin))
+        .try_bind_ephemeral(admin_addr)
+        .expect("Admin listen error");
+    info!("Admin api listening on {}", admin_addr);
+    tokio::spawn(admin_server);
+
+    let message_builder = MessageBuilder::new(
+        config.cache_ttl,
+        config.message_timeout,
+        config.cache_limits.clone(),
+    )
+    .check_duplicate_payload(!config.skip_duplicate_check)
+    .partial_delivery(config.partial_delivery.clone())
+    .deadline_from(config.deadline_from);
+    let message_builder = match &config.state_dir {
+        Some(dir) => {
+            let (state, restored) = StateStore::open(dir).expect("State opening error");
+            message_builder.with_state(state, restored)
+        }
+        None => message_builder,
+    };
+
+    let mut consumer = SegmentConsumer::new(
+        &config.group_id,
+        &config.brokers,
+        config.session_timeout_ms,
+        config.offset_reset,
+    );
+
+    match config.replay {
+        Some(window) => consumer
+            .assign_replay(&topics, window, config.message_timeout)
+            .expect("Replay assignment error"),
+        None => {
+            consumer
+                .reset_offsets(&topics, &config.start)
+                .expect("Offset reset error");
+            consumer.subscribe(&topics);
+        }
+    }
+
+    let consumed = consumer
         .start_consume_and_send(
-            message_sender,
+            delivery,
             message_builder,
-            tokio::time::Duration::from_secs(30),
+            config.housekeeping_interval,
+            config.commit_interval,
+            config.max_concurrent_deliveries,
+            admin_commands,
         )
         .await;
+    if let Err(e) = consumed {
+        error!("consuming failed: {:#}", e);
+        std::process::exit(1);
+    }
 }
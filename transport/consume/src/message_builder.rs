@@ -1,325 +1,1344 @@
-use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono::{DateTime, Duration, Utc};
+use clap::ValueEnum;
 use common::SegmentWithTime;
 use itertools::Itertools;
-use log::{error, info, trace};
-use std::{collections::HashMap, sync::RwLock, vec};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, RwLock},
+};
 
-use super::consumer::Message;
+use super::consumer::{ErrorKind, Gap, Message, MessageError};
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::state::StateStore;
+use crate::validation::{self, Quarantine, Violation};
+
+const QUARANTINE_CAPACITY: usize = 1000;
+
+pub enum Assembled {
+    Pending,
+    Complete(Message),
+    // error message for a message over the cache limits or breaking the protocol
+    Rejected(Message),
+    // segment already received for a pending message
+    Duplicate,
+    // segment of a delivered or expired message
+    Skipped,
+}
 
 pub struct MessageBuilder {
     retry_cache: SegmentsCache,
+    metrics: Arc<Metrics>,
+    quarantine: Mutex<Quarantine>,
+    partial_delivery: Option<PartialDelivery>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PartialDelivery {
+    // put in the payload instead of every missing segment
+    pub placeholder: Option<String>,
 }
 
 impl MessageBuilder {
-    pub fn new(clean_interval: Duration, max_retry_num: u8) -> Self {
+    pub fn new(clean_interval: Duration, max_age: Duration, limits: CacheLimits) -> Self {
+        let metrics = Arc::new(Metrics::default());
+
         Self {
-            retry_cache: SegmentsCache::new(clean_interval, max_retry_num),
+            retry_cache: SegmentsCache::new(clean_interval, max_age, limits, metrics.clone()),
+            metrics,
+            quarantine: Mutex::new(Quarantine::new(QUARANTINE_CAPACITY)),
+            partial_delivery: None,
         }
     }
 
-    pub fn build_messages(&self, got_segments: Vec<SegmentWithTime>) -> Vec<Message> {
-        info!("cache state before: {:?}", &self.retry_cache);
+    // Segments have to pass it before add_segment
+    pub fn check(&self, segment: &SegmentWithTime) -> Result<(), String> {
+        validation::check(segment, self.retry_cache.limits.max_seg_count)
+    }
 
-        let messages = got_segments
-            .into_iter()
-            .group_by(|seg| seg.send_time.clone())
-            .into_iter()
-            .map(|(_, group)| group.collect::<Vec<SegmentWithTime>>())
-            .flat_map(|segments| {
-                let message = self.build_message(segments.clone());
-                if message.is_none() {
-                    self.retry_cache.add_bulk(segments.clone());
-                }
-                message
-            })
-            .collect::<Vec<Message>>();
+    // Segments that can't be decoded or break the protocol never reach the cache
+    pub fn quarantine(&self, violation: Violation) {
+        Metrics::add(&self.metrics.segments_quarantined, 1);
+        self.quarantine.lock().unwrap().add(violation);
+    }
 
-        let messages_with_errors = self.build_messages_with_errors();
+    // Segments of a message that came from another partition than its first segments
+    pub fn misplaced(&self) {
+        Metrics::add(&self.metrics.segments_misplaced, 1);
+    }
 
-        info!("cache state after: {:?}", &self.retry_cache);
+    // Cache backed by the state journal, restored segments wait for the rest
+    // as if they were just received
+    pub fn with_state(mut self, state: StateStore, restored: Vec<SegmentWithTime>) -> Self {
+        self.retry_cache.restore(state, restored);
+        self
+    }
 
-        self.retry_cache.increase_retry();
-        self.retry_cache.clean_old_records();
+    pub fn partial_delivery(mut self, partial_delivery: Option<PartialDelivery>) -> Self {
+        self.partial_delivery = partial_delivery;
+        self
+    }
 
-        info!("messages: {:?}", messages);
-        info!("messages with errors: {:?}", messages_with_errors);
+    pub fn deadline_from(mut self, deadline_from: DeadlineFrom) -> Self {
+        self.retry_cache.deadline_from = deadline_from;
+        self
+    }
 
-        vec![messages, messages_with_errors]
-            .into_iter()
-            .flatten()
-            .collect()
+    pub fn check_duplicate_payload(mut self, check: bool) -> Self {
+        self.retry_cache.check_duplicate_payload = check;
+        self
     }
 
-    fn build_message(&self, got_segments_group: Vec<SegmentWithTime>) -> Option<Message> {
-        let mut segments = got_segments_group;
-        let first_segment = segments.first().unwrap();
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let (pending_messages, pending_bytes) = self.retry_cache.pending();
+        self.metrics.snapshot(pending_messages, pending_bytes)
+    }
 
-        info!("Segments: {:?}", &segments);
+    // Returns the message as soon as its last missing segment arrives
+    pub fn add_segment(&self, segment: SegmentWithTime) -> Assembled {
+        info!("Segment: {:?}", &segment);
+        Metrics::add(&self.metrics.segments_received, 1);
 
-        let send_time = first_segment.send_time.clone();
-        if !self.retry_cache.check_retry_limit(&send_time) {
-            info!("retry limit!");
-            return None;
+        let completed = match self.retry_cache.add(segment) {
+            AddResult::Complete(completed) => completed,
+            AddResult::Rejected(r) => return Assembled::Rejected(Self::error_message(r)),
+            AddResult::Pending => {
+                info!("bitmap not full");
+                return Assembled::Pending;
+            }
+            AddResult::Duplicate => {
+                info!("segment already received, skipping");
+                Metrics::add(&self.metrics.segments_duplicate, 1);
+                return Assembled::Duplicate;
+            }
+            AddResult::Late => {
+                info!("segment of finished message, skipping");
+                Metrics::add(&self.metrics.segments_late, 1);
+                return Assembled::Skipped;
+            }
+        };
+
+        info!("bitmap full");
+
+        let message = Self::build_message(completed);
+        if let Some(error) = &message.error {
+            warn!(
+                "message {}/{} is broken: {}",
+                message.sender, message.send_time, error.reason
+            );
+            Metrics::add(&self.metrics.messages_protocol_error, 1);
+            return Assembled::Rejected(message);
         }
+        info!("message: {:?}", message);
+        Metrics::add(&self.metrics.messages_completed, 1);
+
+        Assembled::Complete(message)
+    }
+
+    // When expire has something to return
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.retry_cache.next_deadline()
+    }
 
-        let sender = first_segment.segment.sender.clone();
-        let segments_count = first_segment.segment.seg_count;
+    // Incomplete messages with passed deadline and evicted ones, reported with has_error
+    pub fn expire(&self) -> Vec<Message> {
+        let messages_with_errors: Vec<Message> = self
+            .retry_cache
+            .take_expired(Utc::now())
+            .into_iter()
+            .map(|r| self.give_up(r))
+            .collect();
 
-        let mut bitmap = vec![false; segments_count];
-        for seg in segments.iter() {
-            bitmap[seg.segment.seg_num] = true;
+        if !messages_with_errors.is_empty() {
+            info!("messages with errors: {:?}", messages_with_errors);
+            info!("cache state: {:?}", &self.retry_cache);
         }
 
-        if !bitmap.iter().all(|b| *b) {
-            info!("bitmap not full");
-            let segments_from_cache = match self.retry_cache.get_segments(&send_time, &bitmap) {
-                Some(s) => s,
-                None => return None,
-            };
-            info!("segments from cache: {:?}", segments_from_cache);
+        messages_with_errors
+    }
+
+    pub fn pending_entries(&self) -> Vec<PendingEntry> {
+        self.retry_cache.pending_entries()
+    }
+
+    // incomplete messages or given up ones expire hasn't returned yet
+    pub fn has_pending(&self) -> bool {
+        self.retry_cache.has_pending()
+    }
+
+    pub fn pending_entry(&self, sender: &str, send_time: &str) -> Option<PendingEntry> {
+        self.retry_cache.pending_entry(sender, send_time)
+    }
+
+    // Error message of a pending message given up on by the admin,
+    // its segments arriving later are skipped
+    pub fn flush(&self, sender: &str, send_time: &str) -> Option<Message> {
+        let flushed = self.retry_cache.invalidate(
+            sender,
+            send_time,
+            ErrorKind::Flushed,
+            "flushed by admin before it was complete",
+        )?;
+        Metrics::add(&self.metrics.messages_flushed, 1);
+
+        Some(Self::error_message(flushed))
+    }
+
+    // Forgets a pending message without delivering anything,
+    // returns its sender and send_time to release the offsets of
+    pub fn drop_pending(&self, sender: &str, send_time: &str) -> Option<(String, String)> {
+        let dropped = self.retry_cache.invalidate(
+            sender,
+            send_time,
+            ErrorKind::Dropped,
+            "dropped by admin",
+        )?;
+        Metrics::add(&self.metrics.messages_dropped, 1);
+        warn!(
+            "dropped message {}/{} with {} of {} segments",
+            dropped.sender, dropped.send_time, dropped.error.received, dropped.error.expected
+        );
 
-            segments.extend(segments_from_cache)
-        } else {
-            info!("bitmap full");
+        Some((dropped.sender, dropped.send_time))
+    }
+
+    // Forgets the messages of revoked partitions without delivering anything,
+    // returns how many of them were pending
+    pub fn forget(&self, messages: &[(String, String)]) -> usize {
+        self.retry_cache.forget(messages)
+    }
+
+    pub fn quarantined(&self) -> Vec<Violation> {
+        self.quarantine.lock().unwrap().entries()
+    }
+
+    // Flushes the state journal to disk
+    pub fn sync_state(&self) {
+        self.retry_cache.sync_state();
+    }
+
+    // Drops finished records older than the clean interval and compacts the state
+    pub fn clean(&self) {
+        self.retry_cache.clean_old_records(Utc::now());
+        self.retry_cache.compact_state();
+    }
+
+    fn error_message(r: InvalidatedRecord) -> Message {
+        Message {
+            payload: "".to_string(),
+            has_error: true,
+            sender: r.sender,
+            send_time: r.send_time,
+            error: Some(r.error),
+            partial: false,
+            gaps: vec![],
         }
+    }
+
+    // Messages which ran out of time are delivered with what arrived when partial delivery is on
+    fn give_up(&self, r: InvalidatedRecord) -> Message {
+        match &self.partial_delivery {
+            Some(partial)
+                if !r.segments.is_empty()
+                    && matches!(
+                        r.error.kind,
+                        ErrorKind::MissingSegments | ErrorKind::Expired
+                    ) =>
+            {
+                Self::partial_message(r, partial)
+            }
+            _ => Self::error_message(r),
+        }
+    }
+
+    fn partial_message(r: InvalidatedRecord, partial: &PartialDelivery) -> Message {
+        let mut segments = r.segments;
+        segments.sort_unstable_by_key(|seg| seg.segment.seg_num);
+
+        let seg_count = r.error.expected;
+
+        // all segments but the last one have the chunk size of split
+        let chunk_size = segments
+            .iter()
+            .find(|seg| seg.segment.seg_num + 1 < seg_count)
+            .map(|seg| seg.segment.payload.len());
+
+        let mut received = segments.into_iter().peekable();
+        let mut payload = String::new();
+        let mut run: Vec<u16> = vec![];
+        let mut gaps = vec![];
+
+        for seg_num in 0..seg_count {
+            match received.next_if(|seg| seg.segment.seg_num == seg_num) {
+                Some(seg) => run.extend(
+                    seg.segment
+                        .payload
+                        .chunks_exact(2)
+                        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]])),
+                ),
+                None => {
+                    // a gap may split a surrogate pair
+                    payload.push_str(&String::from_utf16_lossy(&run));
+                    run.clear();
+
+                    if let Some(placeholder) = &partial.placeholder {
+                        payload.push_str(placeholder);
+                    }
+
+                    let start = chunk_size.map(|size| seg_num * size);
+                    let end = match seg_num + 1 < seg_count {
+                        true => chunk_size.map(|size| (seg_num + 1) * size),
+                        false => None,
+                    };
+                    gaps.push(Gap {
+                        seg_num,
+                        start,
+                        end,
+                    });
+                }
+            }
+        }
+        payload.push_str(&String::from_utf16_lossy(&run));
+
+        Message {
+            payload,
+            has_error: false,
+            sender: r.sender,
+            send_time: r.send_time,
+            error: Some(r.error),
+            partial: true,
+            gaps,
+        }
+    }
+
+    // a message that isn't valid utf-16 is returned with DecodeError
+    fn build_message(completed: Completed) -> Message {
+        let Completed {
+            mut segments,
+            sender,
+            send_time,
+            first_seen,
+            last_seen,
+        } = completed;
+        let expected = segments.len();
 
         segments.sort_unstable_by_key(|seg| seg.segment.seg_num);
 
         let full_payload = segments.into_iter().map(|seg| seg.segment.payload).concat();
         let full_payload_u16: Vec<u16> = full_payload
-            .chunks(2)
-            .into_iter()
-            .map(|chunk| u16::from_le_bytes(chunk.try_into().unwrap()))
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
             .collect();
 
-        let string_payload = String::from_utf16(&full_payload_u16).unwrap();
+        let string_payload = match String::from_utf16(&full_payload_u16) {
+            Ok(payload) => payload,
+            Err(e) => {
+                return Self::error_message(InvalidatedRecord {
+                    sender,
+                    send_time,
+                    segments: vec![],
+                    error: MessageError {
+                        kind: ErrorKind::DecodeError,
+                        reason: e.to_string(),
+                        missing_seg_nums: vec![],
+                        received: expected,
+                        expected,
+                        first_seen: Some(first_seen),
+                        last_seen: Some(last_seen),
+                    },
+                })
+            }
+        };
 
-        Some(Message {
+        Message {
             payload: string_payload,
             has_error: false,
             sender,
             send_time,
+            error: None,
+            partial: false,
+            gaps: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheLimits {
+    pub max_pending_messages: usize,
+    pub max_pending_bytes: usize,
+    pub max_seg_count: usize,
+    pub eviction_policy: EvictionPolicy,
+}
+
+// Incomplete message is given up on at this time plus max age
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DeadlineFrom {
+    // when the first segment was consumed
+    FirstSeen,
+    // when split sent the message, counts the time spent in kafka
+    SendTime,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EvictionPolicy {
+    // message with the earliest first segment
+    Oldest,
+    // message that didn't get new segments for the longest time
+    Lru,
+}
+
+// Incomplete message as shown by the admin api
+#[derive(Debug, Serialize)]
+pub struct PendingEntry {
+    pub sender: String,
+    pub send_time: String,
+    pub seg_count: usize,
+    pub received: Vec<usize>,
+    pub missing: Vec<usize>,
+    // duplicate segments received for the message
+    pub retries: usize,
+    pub bytes: usize,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub deadline: DateTime<Utc>,
+    pub age_secs: i64,
+}
+
+// Segments of different senders sent in the same millisecond are different messages
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    sender: String,
+    send_time: DateTime<Utc>,
+}
+
+impl CacheKey {
+    fn of(seg: &SegmentWithTime) -> Self {
+        Self {
+            sender: seg.segment.sender.clone(),
+            send_time: Self::get_key_from_send_time(&seg.send_time),
+        }
+    }
+
+    // None when send_time isn't a unix time in millis, so no message can have it
+    fn parse(sender: &str, send_time: &str) -> Option<Self> {
+        let send_time = send_time
+            .parse::<i64>()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)?;
+
+        Some(Self {
+            sender: sender.to_owned(),
+            send_time,
         })
     }
 
-    fn build_messages_with_errors(&self) -> Vec<Message> {
-        self.retry_cache
-            .get_latest_invalidated_records()
-            .into_iter()
-            .map(|r| Message {
-                payload: "".to_string(),
-                has_error: true,
-                sender: r.sender,
-                send_time: r.send_time,
-            })
-            .collect()
+    // send_time is checked by validation::check before the segment gets to the cache
+    fn get_key_from_send_time(send_time: &str) -> DateTime<Utc> {
+        send_time
+            .parse::<i64>()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+            .unwrap_or_default()
     }
 }
 
 #[derive(Clone, Debug)]
 struct SegmentsCacheRecord {
+    sender: String,
+    send_time: String,
     segments: Vec<SegmentWithTime>,
     num_bit_map: Vec<bool>,
-    retry: u8,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    deadline: DateTime<Utc>,
+    bytes: usize,
+    // segments received again, redelivered by kafka or resent by a producer retry
+    duplicates: usize,
+    // delivered or expired, segments arriving later are skipped until the record is cleaned
+    finished: bool,
+}
+
+impl SegmentsCacheRecord {
+    fn new(seg: &SegmentWithTime, seg_count: usize, deadline: DateTime<Utc>) -> Self {
+        let now = Utc::now();
+
+        Self {
+            sender: seg.segment.sender.clone(),
+            send_time: seg.send_time.clone(),
+            segments: vec![],
+            num_bit_map: vec![false; seg_count],
+            first_seen: now,
+            last_seen: now,
+            deadline,
+            bytes: 0,
+            duplicates: 0,
+            finished: false,
+        }
+    }
+
+    fn push(&mut self, seg: SegmentWithTime) {
+        self.num_bit_map[seg.segment.seg_num] = true;
+        self.bytes += seg.segment.payload.len();
+        self.last_seen = Utc::now();
+        self.segments.push(seg);
+    }
+
+    fn entry(&self, now: DateTime<Utc>) -> PendingEntry {
+        let (received, missing): (Vec<_>, Vec<_>) =
+            (0..self.num_bit_map.len()).partition(|seg_num| self.num_bit_map[*seg_num]);
+
+        PendingEntry {
+            sender: self.sender.clone(),
+            send_time: self.send_time.clone(),
+            seg_count: self.num_bit_map.len(),
+            received,
+            missing,
+            retries: self.duplicates,
+            bytes: self.bytes,
+            first_seen: self.first_seen,
+            last_seen: self.last_seen,
+            deadline: self.deadline,
+            age_secs: (now - self.first_seen).num_seconds(),
+        }
+    }
+
+    fn error(&self, kind: ErrorKind, reason: &str) -> MessageError {
+        MessageError {
+            kind,
+            reason: reason.to_owned(),
+            missing_seg_nums: self
+                .num_bit_map
+                .iter()
+                .enumerate()
+                .filter(|(_, received)| !**received)
+                .map(|(seg_num, _)| seg_num)
+                .collect(),
+            received: self.num_bit_map.iter().filter(|b| **b).count(),
+            expected: self.num_bit_map.len(),
+            first_seen: Some(self.first_seen),
+            last_seen: Some(self.last_seen),
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 struct InvalidatedRecord {
     sender: String,
     send_time: String,
+    error: MessageError,
+    // segments received before the message was given up on
+    segments: Vec<SegmentWithTime>,
+}
+
+struct Completed {
+    segments: Vec<SegmentWithTime>,
+    sender: String,
+    send_time: String,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+enum AddResult {
+    Pending,
+    // all segments of the message, the record is finished
+    Complete(Completed),
+    // message is over the cache limits or breaks the protocol
+    Rejected(InvalidatedRecord),
+    Duplicate,
+    Late,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    records: HashMap<CacheKey, SegmentsCacheRecord>,
+    pending_messages: usize,
+    pending_bytes: usize,
+    // evicted since the last take_expired
+    evicted: Vec<InvalidatedRecord>,
+    // timer wheel of pending records, finished ones are skipped when their time comes
+    deadlines: BTreeMap<DateTime<Utc>, Vec<CacheKey>>,
+}
+
+impl CacheState {
+    fn insert(&mut self, key: CacheKey, record: SegmentsCacheRecord) {
+        self.deadlines
+            .entry(record.deadline)
+            .or_default()
+            .push(key.clone());
+        self.records.insert(key, record);
+        self.pending_messages += 1;
+    }
+
+    // Turns a pending record into a finished one without segments
+    fn invalidate(
+        &mut self,
+        key: &CacheKey,
+        state: Option<&StateStore>,
+        kind: ErrorKind,
+        reason: &str,
+    ) -> Option<InvalidatedRecord> {
+        let record = self.records.get_mut(key)?;
+        if record.finished {
+            return None;
+        }
+
+        let invalidated = InvalidatedRecord {
+            sender: record.sender.clone(),
+            send_time: record.send_time.clone(),
+            error: record.error(kind, reason),
+            segments: std::mem::take(&mut record.segments),
+        };
+
+        self.pending_messages -= 1;
+        self.pending_bytes -= record.bytes;
+
+        record.finished = true;
+        record.bytes = 0;
+        record.num_bit_map.clear();
+
+        if let Some(state) = state {
+            if let Err(e) = state.finish(&invalidated.sender, &invalidated.send_time) {
+                warn!("failed to write state: {}", e);
+            }
+        }
+
+        Some(invalidated)
+    }
+
+    fn eviction_victim(&self, policy: EvictionPolicy, except: &CacheKey) -> Option<CacheKey> {
+        let candidates = self
+            .records
+            .iter()
+            .filter(|(key, r)| !r.finished && *key != except);
+
+        let victim = match policy {
+            EvictionPolicy::Oldest => candidates.min_by_key(|(_, r)| r.first_seen),
+            EvictionPolicy::Lru => candidates.min_by_key(|(_, r)| r.last_seen),
+        };
+
+        victim.map(|(key, _)| key.clone())
+    }
 }
 
 #[derive(Debug)]
 struct SegmentsCache {
-    cache: RwLock<HashMap<DateTime<Utc>, SegmentsCacheRecord>>,
+    cache: RwLock<CacheState>,
     clean_interval: Duration,
-    max_retry_num: u8,
+    max_age: Duration,
+    deadline_from: DeadlineFrom,
+    limits: CacheLimits,
+    metrics: Arc<Metrics>,
+    state: Option<StateStore>,
+    // a copy of a segment with another payload means corruption on the way
+    check_duplicate_payload: bool,
 }
 
 impl SegmentsCache {
-    pub fn new(clean_interval: Duration, max_retry_num: u8) -> Self {
+    pub fn new(
+        clean_interval: Duration,
+        max_age: Duration,
+        limits: CacheLimits,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
-            cache: RwLock::new(HashMap::new()),
+            cache: RwLock::new(CacheState::default()),
             clean_interval,
-            max_retry_num,
+            max_age,
+            deadline_from: DeadlineFrom::FirstSeen,
+            limits,
+            metrics,
+            state: None,
+            check_duplicate_payload: true,
         }
     }
 
-    pub fn increase_retry(&self) {
-        for (_k, v) in self.write_to_cache().iter_mut() {
-            if v.retry < self.max_retry_num {
-                v.retry += 1;
+    // Restored segments are admitted like consumed ones, so the cache limits and the eviction
+    // policy apply. The journal is rewritten with the segments kept.
+    pub fn restore(&mut self, state: StateStore, segments: Vec<SegmentWithTime>) {
+        for seg in segments {
+            if let Err(e) = validation::check(&seg, self.limits.max_seg_count) {
+                warn!("skipping restored segment: {}", e);
+                continue;
             }
+
+            let key = CacheKey::of(&seg);
+            match self.add(seg) {
+                // its finish entry was lost, consumed again the segments assemble it anew
+                AddResult::Complete(_) => {
+                    self.write_to_cache().records.remove(&key);
+                }
+                AddResult::Rejected(r) => warn!(
+                    "dropping restored message {}/{}: {}",
+                    r.sender, r.send_time, r.error.reason
+                ),
+                _ => {}
+            }
+        }
+
+        let cache = self.cache.read().unwrap();
+        let pending = cache
+            .records
+            .values()
+            .filter(|r| !r.finished)
+            .flat_map(|r| r.segments.iter());
+        if let Err(e) = state.compact(pending) {
+            warn!("failed to compact state: {}", e);
         }
+        drop(cache);
+
+        self.state = Some(state);
     }
 
-    pub fn check_retry_limit(&self, send_time: &str) -> bool {
-        let key = Self::get_key_from_send_time(send_time);
+    // restored records count from the restart with FirstSeen
+    fn deadline_of(&self, key: &CacheKey) -> DateTime<Utc> {
+        match self.deadline_from {
+            DeadlineFrom::FirstSeen => Utc::now() + self.max_age,
+            DeadlineFrom::SendTime => key.send_time + self.max_age,
+        }
+    }
 
-        match self.read_cache().get(&key) {
-            Some(r) => r.retry < self.max_retry_num,
-            None => true,
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        let cache = self.cache.read().unwrap();
+        if !cache.evicted.is_empty() {
+            return Some(Utc::now());
         }
+
+        cache.deadlines.keys().next().copied()
     }
 
-    pub fn get_latest_invalidated_records(&self) -> Vec<InvalidatedRecord> {
-        self.read_cache()
-            .iter()
-            .filter_map(|(_, r)| {
-                let first_segment = match r.segments.get(0) {
-                    Some(s) => s,
-                    None => return None,
-                };
-
-                if r.retry == self.max_retry_num - 1 {
-                    return Some(InvalidatedRecord {
-                        sender: first_segment.segment.sender.clone(),
-                        send_time: first_segment.send_time.clone(),
-                    });
-                }
+    pub fn take_expired(&self, now: DateTime<Utc>) -> Vec<InvalidatedRecord> {
+        let mut cache = self.write_to_cache();
+
+        let mut expired_keys = vec![];
+        while let Some(entry) = cache.deadlines.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            expired_keys.extend(entry.remove());
+        }
 
-                None
+        let mut invalidated: Vec<InvalidatedRecord> = expired_keys
+            .iter()
+            // the key may belong to a newer record after clean_old_records
+            .filter(|key| match cache.records.get(key) {
+                Some(record) => record.deadline <= now,
+                None => false,
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .filter_map(|key| {
+                cache.invalidate(
+                    key,
+                    self.state.as_ref(),
+                    ErrorKind::MissingSegments,
+                    "deadline passed waiting for segments",
+                )
             })
-            .collect()
+            .collect();
+        Metrics::add(&self.metrics.messages_expired, invalidated.len());
+
+        invalidated.append(&mut cache.evicted);
+
+        invalidated
     }
 
-    // to use after check got_bit_map for all true
-    pub fn get_segments(
-        &self,
-        send_time: &str,
-        got_bit_map: &[bool],
-    ) -> Option<Vec<SegmentWithTime>> {
-        let key = Self::get_key_from_send_time(send_time);
-        info!("get segments, key: {:?}", key);
-
-        let record = match self.read_cache().get(&key) {
-            Some(r) => r.clone(),
-            None => return None,
-        };
+    // seg_count of the segment is within the limits, see validation::check
+    pub fn add(&self, seg: SegmentWithTime) -> AddResult {
+        let key = CacheKey::of(&seg);
 
-        info!("get segments, record: {:?}", record);
+        let seg_num = seg.segment.seg_num;
+        let seg_count = seg.segment.seg_count;
+        let bytes = seg.segment.payload.len();
+
+        let mut cache = self.write_to_cache();
 
-        if record.num_bit_map.len() != got_bit_map.len() {
-            info!("bit_map wrong size");
-            panic!("bit_map wrong size");
+        if !cache.records.contains_key(&key) {
+            if cache.pending_messages >= self.limits.max_pending_messages {
+                self.evict(&mut cache, &key);
+            }
+
+            let record = SegmentsCacheRecord::new(&seg, seg_count, self.deadline_of(&key));
+            cache.insert(key.clone(), record);
         }
 
-        let mut bitmap_all_true = true;
+        let record = cache.records.get_mut(&key).unwrap();
 
-        for (ind, stored) in record.num_bit_map.iter().enumerate() {
-            if !stored && !got_bit_map[ind] {
-                bitmap_all_true = false;
-                break;
-            }
+        // late segments keep the record of a finished message until they stop coming
+        if record.finished {
+            record.last_seen = Utc::now();
+            return AddResult::Late;
         }
 
-        info!("get segments, bitmap_all_true: {}", bitmap_all_true);
+        // every segment of a message must carry the same seg_count
+        if record.num_bit_map.len() != seg_count || seg_num >= seg_count {
+            let reason = format!(
+                "segment {}/{} doesn't match seg_count {} of the message",
+                seg_num,
+                seg_count,
+                record.num_bit_map.len()
+            );
+            Metrics::add(&self.metrics.messages_protocol_error, 1);
+            return self.reject(&mut cache, &key, ErrorKind::DecodeError, &reason);
+        }
 
-        if !bitmap_all_true {
-            return None;
+        // redelivered after a restart or a rebalance, resent by a producer retry
+        if record.num_bit_map[seg_num] {
+            let conflicting = self.check_duplicate_payload
+                && record.segments.iter().any(|s| {
+                    s.segment.seg_num == seg_num && s.segment.payload != seg.segment.payload
+                });
+
+            if conflicting {
+                Metrics::add(&self.metrics.segments_conflicting, 1);
+                let reason = format!("segment {} came twice with different payloads", seg_num);
+                return self.reject(&mut cache, &key, ErrorKind::ChecksumMismatch, &reason);
+            }
+
+            cache.records.get_mut(&key).unwrap().duplicates += 1;
+            return AddResult::Duplicate;
         }
 
-        let stored_segments = &record.segments;
+        let finished = record.num_bit_map.iter().filter(|b| !**b).count() == 1;
 
-        let segments: Vec<SegmentWithTime> = record
-            .num_bit_map
-            .iter()
-            .zip(got_bit_map)
-            .enumerate()
-            .filter_map(|(ind, (stored, got))| {
-                if *stored && !got {
-                    let stored_segment = stored_segments
-                        .iter()
-                        .find(|s| s.segment.seg_num == ind)
-                        .unwrap();
-
-                    Some(stored_segment.clone())
-                } else {
-                    None
+        if !finished {
+            while cache.pending_bytes + bytes > self.limits.max_pending_bytes {
+                if !self.evict(&mut cache, &key) {
+                    break;
                 }
-            })
-            .collect();
+            }
+
+            // the message alone doesn't fit
+            if cache.pending_bytes + bytes > self.limits.max_pending_bytes {
+                let reason = format!("over {} pending bytes", self.limits.max_pending_bytes);
+                Metrics::add(&self.metrics.messages_rejected, 1);
+                return self.reject(&mut cache, &key, ErrorKind::Evicted, &reason);
+            }
+        }
+
+        if let Some(state) = &self.state {
+            let written = match finished {
+                true => state.finish(&seg.segment.sender, &seg.send_time),
+                false => state.add(&seg),
+            };
+            if let Err(e) = written {
+                warn!("failed to write state: {}", e);
+            }
+        }
+
+        cache.pending_bytes += bytes;
+        let record = cache.records.get_mut(&key).unwrap();
+        record.push(seg);
 
-        Some(segments)
+        if !finished {
+            return AddResult::Pending;
+        }
+
+        let record_bytes = record.bytes;
+        record.finished = true;
+        record.bytes = 0;
+        record.num_bit_map.clear();
+        let completed = Completed {
+            segments: std::mem::take(&mut record.segments),
+            sender: record.sender.clone(),
+            send_time: record.send_time.clone(),
+            first_seen: record.first_seen,
+            last_seen: record.last_seen,
+        };
+
+        cache.pending_messages -= 1;
+        cache.pending_bytes -= record_bytes;
+
+        AddResult::Complete(completed)
     }
 
-    pub fn add_bulk(&self, segmets: Vec<SegmentWithTime>) {
-        for s in segmets {
-            self.add(s);
+    // the record is pending, so it is always invalidated
+    fn reject(
+        &self,
+        cache: &mut CacheState,
+        key: &CacheKey,
+        kind: ErrorKind,
+        reason: &str,
+    ) -> AddResult {
+        match cache.invalidate(key, self.state.as_ref(), kind, reason) {
+            Some(invalidated) => {
+                warn!(
+                    "rejecting message {}/{}: {}",
+                    invalidated.sender, invalidated.send_time, reason
+                );
+                AddResult::Rejected(invalidated)
+            }
+            None => AddResult::Late,
         }
     }
 
-    pub fn add(&self, seg: SegmentWithTime) {
-        let key = Self::get_key_from_send_time(&seg.send_time);
+    // returns false when there is nothing to evict
+    fn evict(&self, cache: &mut CacheState, except: &CacheKey) -> bool {
+        let victim = match cache.eviction_victim(self.limits.eviction_policy, except) {
+            Some(victim) => victim,
+            None => return false,
+        };
 
-        let seg_num = seg.segment.seg_num;
-        let seg_count = seg.segment.seg_count;
+        if let Some(evicted) = cache.invalidate(
+            &victim,
+            self.state.as_ref(),
+            ErrorKind::Evicted,
+            "cache limits reached",
+        ) {
+            warn!(
+                "cache limits reached, evicted message {}/{}",
+                evicted.sender, evicted.send_time
+            );
+            Metrics::add(&self.metrics.messages_evicted, 1);
+            cache.evicted.push(evicted);
+        }
 
-        let record_to_insert = match self.write_to_cache().get_mut(&key) {
-            Some(rec) => {
-                rec.segments.push(seg);
-                rec.num_bit_map[seg_num] = true;
-                None
-            }
-            None => {
-                let mut num_bit_map = vec![false; seg_count];
-                num_bit_map[seg_num] = true;
+        true
+    }
 
-                let segments = vec![seg];
+    pub fn clean_old_records(&self, now: DateTime<Utc>) {
+        let mut cache = self.write_to_cache();
 
-                let record = SegmentsCacheRecord {
-                    segments,
-                    num_bit_map,
-                    retry: 0,
-                };
+        // by the time segments were consumed, replayed and caught up messages are old by send_time
+        let old_keys: Vec<CacheKey> = cache
+            .records
+            .iter()
+            .filter(|(_, record)| now - record.last_seen >= self.clean_interval)
+            .map(|(key, _)| key.clone())
+            .collect();
 
-                Some(record)
-                //self.write_to_cache().insert(key, record);
+        // pending ones are reported with the evicted
+        for key in old_keys {
+            let expired = cache.invalidate(
+                &key,
+                self.state.as_ref(),
+                ErrorKind::Expired,
+                "no segments for the clean interval",
+            );
+            if let Some(expired) = expired {
+                Metrics::add(&self.metrics.messages_expired, 1);
+                cache.evicted.push(expired);
             }
-        };
+            cache.records.remove(&key);
+        }
+    }
 
-        match record_to_insert {
-            Some(r) => {
-                self.write_to_cache().insert(key, r);
+    pub fn sync_state(&self) {
+        if let Some(state) = &self.state {
+            if let Err(e) = state.sync() {
+                warn!("failed to sync state: {}", e);
             }
-            None => return,
         }
     }
 
-    pub fn remove(&self, key: &DateTime<Utc>) -> bool {
-        self.write_to_cache().remove(key).is_some()
+    // Rewrites the state journal with pending segments only
+    pub fn compact_state(&self) {
+        let state = match &self.state {
+            Some(state) if state.needs_compaction() => state,
+            _ => return,
+        };
+
+        let cache = self.cache.read().unwrap();
+        let pending = cache
+            .records
+            .values()
+            .filter(|r| !r.finished)
+            .flat_map(|r| r.segments.iter());
+
+        if let Err(e) = state.compact(pending) {
+            warn!("failed to compact state: {}", e);
+        }
     }
 
-    pub fn clean_old_records(&self) {
+    pub fn pending_entries(&self) -> Vec<PendingEntry> {
+        let cache = self.cache.read().unwrap();
         let now = Utc::now();
 
-        self.write_to_cache()
-            .retain(|key, record| (now - *key < self.clean_interval));
+        let mut entries: Vec<PendingEntry> = cache
+            .records
+            .values()
+            .filter(|r| !r.finished)
+            .map(|r| r.entry(now))
+            .collect();
+        entries.sort_unstable_by_key(|e| e.first_seen);
+
+        entries
+    }
+
+    pub fn pending_entry(&self, sender: &str, send_time: &str) -> Option<PendingEntry> {
+        let key = CacheKey::parse(sender, send_time)?;
+        let cache = self.cache.read().unwrap();
+
+        cache
+            .records
+            .get(&key)
+            .filter(|r| !r.finished)
+            .map(|r| r.entry(Utc::now()))
+    }
+
+    // Gives up on a pending message now, None when there is no such pending message
+    pub fn invalidate(
+        &self,
+        sender: &str,
+        send_time: &str,
+        kind: ErrorKind,
+        reason: &str,
+    ) -> Option<InvalidatedRecord> {
+        let key = CacheKey::parse(sender, send_time)?;
+        let mut cache = self.write_to_cache();
+
+        cache.invalidate(&key, self.state.as_ref(), kind, reason)
+    }
+
+    // Removes the records, segments of the messages consumed again start new ones
+    pub fn forget(&self, messages: &[(String, String)]) -> usize {
+        let mut cache = self.write_to_cache();
+        let mut forgotten = 0;
+
+        for (sender, send_time) in messages {
+            let key = match CacheKey::parse(sender, send_time) {
+                Some(key) => key,
+                None => continue,
+            };
 
-        for (_k, v) in self.write_to_cache().iter_mut() {
-            if v.retry == self.max_retry_num {
-                v.segments.clear();
-                v.num_bit_map.clear();
+            let pending = cache.invalidate(
+                &key,
+                self.state.as_ref(),
+                ErrorKind::Evicted,
+                "partition revoked",
+            );
+            if pending.is_some() {
+                forgotten += 1;
             }
+            cache.records.remove(&key);
+            cache
+                .evicted
+                .retain(|r| r.sender != *sender || r.send_time != *send_time);
         }
+
+        forgotten
     }
 
-    fn read_cache(
-        &self,
-    ) -> std::sync::RwLockReadGuard<'_, HashMap<DateTime<Utc>, SegmentsCacheRecord>> {
-        self.cache.read().unwrap()
+    pub fn has_pending(&self) -> bool {
+        let cache = self.cache.read().unwrap();
+        cache.pending_messages > 0 || !cache.evicted.is_empty()
     }
 
-    fn write_to_cache(
-        &self,
-    ) -> std::sync::RwLockWriteGuard<'_, HashMap<DateTime<Utc>, SegmentsCacheRecord>> {
+    pub fn pending(&self) -> (usize, usize) {
+        let cache = self.cache.read().unwrap();
+        (cache.pending_messages, cache.pending_bytes)
+    }
+
+    fn write_to_cache(&self) -> std::sync::RwLockWriteGuard<'_, CacheState> {
         self.cache.write().unwrap()
     }
+}
 
-    fn get_key_from_send_time(send_time: &str) -> DateTime<Utc> {
-        let timestamp_key: usize = send_time.parse().unwrap();
-        let key: DateTime<Utc> = DateTime::from_timestamp_millis(timestamp_key as i64).unwrap();
+#[cfg(test)]
+mod tests {
+    use common::Segment;
+
+    use super::*;
+
+    fn limits() -> CacheLimits {
+        CacheLimits {
+            max_pending_messages: 100,
+            max_pending_bytes: 1 << 20,
+            max_seg_count: 100,
+            eviction_policy: EvictionPolicy::Oldest,
+        }
+    }
+
+    fn builder() -> MessageBuilder {
+        MessageBuilder::new(Duration::hours(1), Duration::minutes(1), limits())
+    }
+
+    fn segment(send_time: &str, seg_num: usize, seg_count: usize) -> SegmentWithTime {
+        SegmentWithTime {
+            segment: Segment {
+                payload: vec![b'a', 0],
+                seg_count,
+                seg_num,
+                sender: "sender".to_owned(),
+            },
+            send_time: send_time.to_owned(),
+        }
+    }
+
+    // utf-16 payload as split produces it
+    fn segment_of(
+        sender: &str,
+        send_time: &str,
+        seg_num: usize,
+        seg_count: usize,
+        text: &str,
+    ) -> SegmentWithTime {
+        SegmentWithTime {
+            segment: Segment {
+                payload: text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+                seg_count,
+                seg_num,
+                sender: sender.to_owned(),
+            },
+            send_time: send_time.to_owned(),
+        }
+    }
+
+    fn state_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("consume-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn huge_seg_count_is_refused_before_the_cache() {
+        let builder = builder();
+        let seg = segment("1700000000000", 0, usize::MAX);
+
+        assert!(builder.check(&seg).is_err());
+        assert!(!builder.has_pending());
+    }
+
+    #[test]
+    fn huge_seg_count_is_skipped_on_restore() {
+        let dir = state_dir("restore-seg-count");
+        let (state, _) = StateStore::open(&dir).unwrap();
+
+        let builder = builder().with_state(
+            state,
+            vec![
+                segment("1700000000000", 0, usize::MAX),
+                segment("1700000000001", 0, 2),
+            ],
+        );
+
+        let pending = builder.pending_entries();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].send_time, "1700000000001");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn forgotten_messages_are_assembled_again() {
+        let builder = builder();
+        let key = ("sender".to_owned(), "1700000000000".to_owned());
+
+        assert!(matches!(
+            builder.add_segment(segment("1700000000000", 0, 2)),
+            Assembled::Pending
+        ));
+        assert_eq!(builder.forget(&[key]), 1);
+        assert!(!builder.has_pending());
+        assert!(builder.expire().is_empty());
+
+        // consumed again after the partition comes back
+        builder.add_segment(segment("1700000000000", 0, 2));
+        assert!(matches!(
+            builder.add_segment(segment("1700000000000", 1, 2)),
+            Assembled::Complete(_)
+        ));
+    }
+
+    #[test]
+    fn old_send_time_does_not_expire_pending_messages() {
+        let builder = builder();
+        // replayed from a window long before the cache ttl
+        builder.add_segment(segment("1000000000000", 0, 2));
+
+        builder.clean();
+        assert!(builder.expire().is_empty());
+        assert!(matches!(
+            builder.add_segment(segment("1000000000000", 1, 2)),
+            Assembled::Complete(_)
+        ));
+
+        builder.clean();
+        assert!(matches!(
+            builder.add_segment(segment("1000000000000", 1, 2)),
+            Assembled::Skipped
+        ));
+    }
+
+    #[test]
+    fn dropped_messages_are_not_counted_as_flushed() {
+        let builder = builder();
+        builder.add_segment(segment("1700000000000", 0, 2));
+
+        assert_eq!(
+            builder.drop_pending("sender", "1700000000000"),
+            Some(("sender".to_owned(), "1700000000000".to_owned()))
+        );
+        let metrics = builder.metrics();
+        assert_eq!(metrics.messages_dropped, 1);
+        assert_eq!(metrics.messages_flushed, 0);
+        assert!(builder.flush("sender", "1700000000000").is_none());
+        assert!(matches!(
+            builder.add_segment(segment("1700000000000", 1, 2)),
+            Assembled::Skipped
+        ));
+    }
+
+    #[test]
+    fn restore_applies_the_cache_limits() {
+        let dir = state_dir("restore-limits");
+        let (state, _) = StateStore::open(&dir).unwrap();
+        let limits = CacheLimits {
+            max_pending_messages: 2,
+            ..limits()
+        };
+
+        let builder = MessageBuilder::new(Duration::hours(1), Duration::minutes(1), limits)
+            .with_state(
+                state,
+                vec![
+                    segment("1700000000000", 0, 2),
+                    segment("1700000000001", 0, 2),
+                    segment("1700000000002", 0, 2),
+                ],
+            );
+
+        let mut pending: Vec<String> = builder
+            .pending_entries()
+            .into_iter()
+            .map(|e| e.send_time)
+            .collect();
+        pending.sort();
+        assert_eq!(pending, vec!["1700000000001", "1700000000002"]);
+        assert_eq!(builder.metrics().messages_evicted, 1);
+
+        // the journal keeps only what was admitted
+        drop(builder);
+        let (_, restored) = StateStore::open(&dir).unwrap();
+        assert_eq!(restored.len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn same_send_time_of_other_senders_is_another_message() {
+        let builder = builder();
+
+        builder.add_segment(segment_of("a", "1700000000000", 0, 2, "a0"));
+        builder.add_segment(segment_of("b", "1700000000000", 0, 2, "b0"));
+
+        let message = match builder.add_segment(segment_of("a", "1700000000000", 1, 2, "a1")) {
+            Assembled::Complete(message) => message,
+            _ => panic!("message of a is not complete"),
+        };
+        assert_eq!(message.sender, "a");
+        assert_eq!(message.payload, "a0a1");
+
+        let pending = builder.pending_entries();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].sender, "b");
+    }
+
+    #[test]
+    fn conflicting_seg_count_is_a_decode_error() {
+        let builder = builder();
+
+        builder.add_segment(segment_of("a", "1700000000000", 0, 2, "a0"));
+        let message = match builder.add_segment(segment_of("a", "1700000000000", 1, 3, "a1")) {
+            Assembled::Rejected(message) => message,
+            _ => panic!("conflicting segment is not rejected"),
+        };
+
+        assert!(message.has_error);
+        assert_eq!(message.error.unwrap().kind, ErrorKind::DecodeError);
+        assert!(!builder.has_pending());
+    }
+
+    #[test]
+    fn identical_duplicate_is_ignored() {
+        let builder = builder();
+
+        builder.add_segment(segment_of("a", "1700000000000", 0, 2, "a0"));
+        assert!(matches!(
+            builder.add_segment(segment_of("a", "1700000000000", 0, 2, "a0")),
+            Assembled::Duplicate
+        ));
+        assert_eq!(builder.metrics().segments_duplicate, 1);
+
+        let message = match builder.add_segment(segment_of("a", "1700000000000", 1, 2, "a1")) {
+            Assembled::Complete(message) => message,
+            _ => panic!("message is not complete"),
+        };
+        assert_eq!(message.payload, "a0a1");
+    }
+
+    #[test]
+    fn differing_duplicate_is_a_checksum_mismatch() {
+        let builder = builder();
+
+        builder.add_segment(segment_of("a", "1700000000000", 0, 2, "a0"));
+        let message = match builder.add_segment(segment_of("a", "1700000000000", 0, 2, "b0")) {
+            Assembled::Rejected(message) => message,
+            _ => panic!("differing duplicate is not rejected"),
+        };
+
+        assert_eq!(message.error.unwrap().kind, ErrorKind::ChecksumMismatch);
+        assert_eq!(builder.metrics().segments_conflicting, 1);
+        assert!(!builder.has_pending());
+    }
+
+    #[test]
+    fn partial_message_marks_missing_middle_and_tail() {
+        let builder = builder()
+            .deadline_from(DeadlineFrom::SendTime)
+            .partial_delivery(Some(PartialDelivery {
+                placeholder: Some("?".to_owned()),
+            }));
+
+        // sent long ago, the deadline has passed
+        builder.add_segment(segment_of("a", "1000000000000", 0, 4, "s0"));
+        builder.add_segment(segment_of("a", "1000000000000", 2, 4, "s2"));
+
+        let messages = builder.expire();
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0];
+
+        assert!(message.partial);
+        assert!(!message.has_error);
+        assert_eq!(message.payload, "s0?s2?");
+        assert_eq!(message.error.as_ref().unwrap().missing_seg_nums, vec![1, 3]);
+
+        // segments are 4 bytes of utf-16, the size of the last one is unknown
+        let gaps: Vec<(usize, Option<usize>, Option<usize>)> = message
+            .gaps
+            .iter()
+            .map(|g| (g.seg_num, g.start, g.end))
+            .collect();
+        assert_eq!(gaps, vec![(1, Some(4), Some(8)), (3, Some(12), None)]);
+    }
+
+    #[test]
+    fn messages_expire_at_their_own_deadlines() {
+        // deadlines are a minute after the send_time
+        let builder = builder().deadline_from(DeadlineFrom::SendTime);
+        let sent = |millis: i64| DateTime::from_timestamp_millis(millis).unwrap();
+
+        builder.add_segment(segment_of("a", "1700000000000", 0, 2, "a0"));
+        builder.add_segment(segment_of("a", "1700000030000", 0, 2, "b0"));
+        // completed before its deadline
+        builder.add_segment(segment_of("a", "1700000010000", 0, 2, "c0"));
+        builder.add_segment(segment_of("a", "1700000010000", 1, 2, "c1"));
+
+        let send_times = |records: Vec<InvalidatedRecord>| -> Vec<String> {
+            records.into_iter().map(|r| r.send_time).collect()
+        };
+        let cache = &builder.retry_cache;
 
-        key
+        assert!(cache.take_expired(sent(1700000059999)).is_empty());
+        assert_eq!(
+            send_times(cache.take_expired(sent(1700000075000))),
+            vec!["1700000000000"]
+        );
+        assert_eq!(
+            send_times(cache.take_expired(sent(1700000090000))),
+            vec!["1700000030000"]
+        );
+        assert!(cache.take_expired(sent(1800000000000)).is_empty());
+        assert!(cache.next_deadline().is_none());
     }
 }
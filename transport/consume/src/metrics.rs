@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+// Counters of the reassembly, logged periodically by the consumer
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub segments_received: AtomicUsize,
+    pub segments_duplicate: AtomicUsize,
+    pub segments_conflicting: AtomicUsize,
+    pub segments_late: AtomicUsize,
+    pub segments_quarantined: AtomicUsize,
+    pub segments_misplaced: AtomicUsize,
+    pub messages_completed: AtomicUsize,
+    pub messages_expired: AtomicUsize,
+    pub messages_evicted: AtomicUsize,
+    pub messages_rejected: AtomicUsize,
+    pub messages_protocol_error: AtomicUsize,
+    pub messages_flushed: AtomicUsize,
+    pub messages_dropped: AtomicUsize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub segments_received: usize,
+    pub segments_duplicate: usize,
+    pub segments_conflicting: usize,
+    pub segments_late: usize,
+    pub segments_quarantined: usize,
+    pub segments_misplaced: usize,
+    pub messages_completed: usize,
+    pub messages_expired: usize,
+    pub messages_evicted: usize,
+    pub messages_rejected: usize,
+    pub messages_protocol_error: usize,
+    pub messages_flushed: usize,
+    pub messages_dropped: usize,
+    pub pending_messages: usize,
+    pub pending_bytes: usize,
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicUsize, n: usize) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, pending_messages: usize, pending_bytes: usize) -> MetricsSnapshot {
+        MetricsSnapshot {
+            segments_received: self.segments_received.load(Ordering::Relaxed),
+            segments_duplicate: self.segments_duplicate.load(Ordering::Relaxed),
+            segments_conflicting: self.segments_conflicting.load(Ordering::Relaxed),
+            segments_late: self.segments_late.load(Ordering::Relaxed),
+            segments_quarantined: self.segments_quarantined.load(Ordering::Relaxed),
+            segments_misplaced: self.segments_misplaced.load(Ordering::Relaxed),
+            messages_completed: self.messages_completed.load(Ordering::Relaxed),
+            messages_expired: self.messages_expired.load(Ordering::Relaxed),
+            messages_evicted: self.messages_evicted.load(Ordering::Relaxed),
+            messages_rejected: self.messages_rejected.load(Ordering::Relaxed),
+            messages_protocol_error: self.messages_protocol_error.load(Ordering::Relaxed),
+            messages_flushed: self.messages_flushed.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            pending_messages,
+            pending_bytes,
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+use crate::seek::PartitionKey;
+
+// sender and send_time of the message
+pub type MessageKey = (String, String);
+
+#[derive(Clone, Debug)]
+pub struct SegmentOffset {
+    topic: String,
+    partition: i32,
+    offset: i64,
+    // of the partition when the segment was tracked
+    generation: u64,
+}
+
+#[derive(Default)]
+struct PartitionOffsets {
+    // offsets of segments whose message is not delivered yet
+    pending: BTreeSet<i64>,
+    next: i64,
+    committed: Option<i64>,
+    // changes when the partition is revoked, deliveries finishing after it release nothing
+    generation: u64,
+}
+
+impl PartitionOffsets {
+    // everything below it is delivered or given up on
+    fn position(&self) -> i64 {
+        self.pending.first().copied().unwrap_or(self.next)
+    }
+}
+
+#[derive(Default)]
+struct Offsets {
+    partitions: HashMap<PartitionKey, PartitionOffsets>,
+    messages: HashMap<MessageKey, Vec<SegmentOffset>>,
+    generation: u64,
+}
+
+impl Offsets {
+    fn partition(&mut self, topic: &str, partition: i32) -> &mut PartitionOffsets {
+        let generation = self.generation;
+
+        self.partitions
+            .entry((topic.to_owned(), partition))
+            .or_insert_with(|| PartitionOffsets {
+                generation,
+                ..Default::default()
+            })
+    }
+}
+
+// Offsets are committed only up to the first segment of a not yet delivered message,
+// so after a restart undelivered messages are consumed again (and delivered ones may be
+// delivered twice).
+#[derive(Default)]
+pub struct OffsetTracker {
+    inner: Mutex<Offsets>,
+}
+
+impl OffsetTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn track(
+        &self,
+        key: &MessageKey,
+        topic: &str,
+        partition: i32,
+        offset: i64,
+    ) -> SegmentOffset {
+        let mut inner = self.inner.lock().unwrap();
+
+        let partition_offsets = inner.partition(topic, partition);
+        partition_offsets.pending.insert(offset);
+        partition_offsets.next = partition_offsets.next.max(offset + 1);
+
+        let segment_offset = SegmentOffset {
+            topic: topic.to_owned(),
+            partition,
+            offset,
+            generation: partition_offsets.generation,
+        };
+
+        inner
+            .messages
+            .entry(key.clone())
+            .or_default()
+            .push(segment_offset.clone());
+
+        segment_offset
+    }
+
+    // Segment that doesn't belong to any message, e.g. quarantined
+    pub fn skip(&self, topic: &str, partition: i32, offset: i64) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let partition_offsets = inner.partition(topic, partition);
+        partition_offsets.next = partition_offsets.next.max(offset + 1);
+    }
+
+    // Offsets of the message are held until release, segments of the same key consumed
+    // later are tracked separately
+    pub fn take(&self, key: &MessageKey) -> Vec<SegmentOffset> {
+        self.inner
+            .lock()
+            .unwrap()
+            .messages
+            .remove(key)
+            .unwrap_or_default()
+    }
+
+    pub fn release(&self, offsets: &[SegmentOffset]) {
+        let mut inner = self.inner.lock().unwrap();
+
+        for o in offsets {
+            match inner.partitions.get_mut(&(o.topic.clone(), o.partition)) {
+                Some(partition_offsets) if partition_offsets.generation == o.generation => {
+                    partition_offsets.pending.remove(&o.offset);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Forgets the partitions, their offsets are committed by the caller first.
+    // Returns the messages with segments from them, the next owner consumes them again.
+    pub fn revoke(&self, partitions: &[PartitionKey]) -> Vec<MessageKey> {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.generation += 1;
+        for key in partitions {
+            inner.partitions.remove(key);
+        }
+
+        let revoked: Vec<MessageKey> = inner
+            .messages
+            .iter()
+            .filter(|(_, offsets)| {
+                offsets
+                    .iter()
+                    .any(|o| partitions.contains(&(o.topic.clone(), o.partition)))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        // segments of the messages from partitions kept don't hold their commits anymore
+        let mut released = vec![];
+        for key in &revoked {
+            released.extend(inner.messages.remove(key).unwrap_or_default());
+        }
+        drop(inner);
+        self.release(&released);
+
+        revoked
+    }
+
+    // Partitions whose position moved since the last call
+    pub fn to_commit(&self) -> Option<TopicPartitionList> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut list = TopicPartitionList::new();
+
+        for ((topic, partition), partition_offsets) in inner.partitions.iter_mut() {
+            let position = partition_offsets.position();
+            if partition_offsets.committed == Some(position) {
+                continue;
+            }
+
+            if list
+                .add_partition_offset(topic, *partition, Offset::Offset(position))
+                .is_ok()
+            {
+                partition_offsets.committed = Some(position);
+            }
+        }
+
+        (list.count() > 0).then_some(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(send_time: &str) -> MessageKey {
+        ("sender".to_owned(), send_time.to_owned())
+    }
+
+    fn committed(tracker: &OffsetTracker, partition: i32) -> Option<i64> {
+        let list = tracker.to_commit()?;
+        let offset = list.find_partition("topic", partition)?.offset();
+        match offset {
+            Offset::Offset(offset) => Some(offset),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn commits_up_to_the_first_undelivered_message() {
+        let tracker = OffsetTracker::new();
+        tracker.track(&key("1"), "topic", 0, 10);
+        tracker.track(&key("2"), "topic", 0, 11);
+        tracker.track(&key("1"), "topic", 0, 12);
+
+        assert_eq!(committed(&tracker, 0), Some(10));
+
+        // message 2 is delivered before message 1
+        tracker.release(&tracker.take(&key("2")));
+        assert_eq!(committed(&tracker, 0), None);
+
+        tracker.release(&tracker.take(&key("1")));
+        assert_eq!(committed(&tracker, 0), Some(13));
+    }
+
+    #[test]
+    fn to_commit_returns_moved_partitions_only() {
+        let tracker = OffsetTracker::new();
+        tracker.track(&key("1"), "topic", 0, 5);
+        tracker.track(&key("2"), "topic", 1, 7);
+
+        let list = tracker.to_commit().unwrap();
+        assert_eq!(list.count(), 2);
+        assert!(tracker.to_commit().is_none());
+
+        tracker.release(&tracker.take(&key("2")));
+        let list = tracker.to_commit().unwrap();
+        assert_eq!(list.count(), 1);
+        assert_eq!(
+            list.find_partition("topic", 1).unwrap().offset(),
+            Offset::Offset(8)
+        );
+    }
+
+    #[test]
+    fn skipped_segments_move_the_position() {
+        let tracker = OffsetTracker::new();
+        tracker.skip("topic", 0, 3);
+
+        assert_eq!(committed(&tracker, 0), Some(4));
+    }
+
+    #[test]
+    fn release_after_revoke_is_ignored() {
+        let tracker = OffsetTracker::new();
+        tracker.track(&key("1"), "topic", 0, 10);
+        tracker.track(&key("2"), "topic", 0, 11);
+        let delivering = tracker.take(&key("1"));
+
+        assert_eq!(tracker.revoke(&[("topic".to_owned(), 0)]), vec![key("2")]);
+        assert!(tracker.take(&key("2")).is_empty());
+
+        // the partition comes back and its segments are consumed again
+        tracker.track(&key("1"), "topic", 0, 10);
+        tracker.release(&delivering);
+
+        assert_eq!(committed(&tracker, 0), Some(10));
+    }
+
+    #[test]
+    fn revoke_releases_segments_of_kept_partitions() {
+        let tracker = OffsetTracker::new();
+        tracker.track(&key("1"), "topic", 0, 10);
+        tracker.track(&key("1"), "topic", 1, 20);
+
+        tracker.revoke(&[("topic".to_owned(), 0)]);
+
+        assert_eq!(committed(&tracker, 1), Some(21));
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::{Arc, OnceLock, Weak};
+
+use log::{error, info};
+
+use rdkafka::client::ClientContext;
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::error::KafkaResult;
+use rdkafka::topic_partition_list::TopicPartitionList;
+
+use crate::message_builder::MessageBuilder;
+use crate::offsets::OffsetTracker;
+use crate::seek::PartitionKey;
+
+// State of the consume loop the callbacks work on
+struct Attached {
+    consumer: Weak<StreamConsumer<SegmentConsumerContext>>,
+    offsets: Arc<OffsetTracker>,
+    message_builder: Arc<MessageBuilder>,
+}
+
+// Rebalance callbacks run inside recv of the consume loop. Before partitions are revoked
+// their offsets are committed up to the first undelivered message and the segments read
+// from them are forgotten, the next owner consumes them again.
+#[derive(Clone, Default)]
+pub struct SegmentConsumerContext {
+    attached: Arc<OnceLock<Attached>>,
+}
+
+impl SegmentConsumerContext {
+    pub fn attach(
+        &self,
+        consumer: Weak<StreamConsumer<SegmentConsumerContext>>,
+        offsets: Arc<OffsetTracker>,
+        message_builder: Arc<MessageBuilder>,
+    ) {
+        let attached = Attached {
+            consumer,
+            offsets,
+            message_builder,
+        };
+        if self.attached.set(attached).is_err() {
+            error!("consume loop is already attached to the consumer");
+        }
+    }
+
+    fn revoke(&self) {
+        let attached = match self.attached.get() {
+            Some(attached) => attached,
+            None => return,
+        };
+        let consumer = match attached.consumer.upgrade() {
+            Some(consumer) => consumer,
+            None => return,
+        };
+
+        // the rebalance is eager, every assigned partition is revoked
+        let partitions: Vec<PartitionKey> = match consumer.assignment() {
+            Ok(assignment) => assignment
+                .elements()
+                .iter()
+                .map(|e| (e.topic().to_owned(), e.partition()))
+                .collect(),
+            Err(e) => {
+                error!("failed to get revoked partitions: {}", e);
+                return;
+            }
+        };
+
+        if let Some(list) = attached.offsets.to_commit() {
+            info!("committing offsets of revoked partitions: {:?}", list);
+            if let Err(e) = consumer.commit(&list, CommitMode::Sync) {
+                error!("failed to commit offsets of revoked partitions: {}", e);
+            }
+        }
+
+        let messages = attached.offsets.revoke(&partitions);
+        let forgotten = attached.message_builder.forget(&messages);
+        info!(
+            "revoked {:?}, {} pending messages forgotten",
+            partitions, forgotten
+        );
+    }
+}
+
+impl ClientContext for SegmentConsumerContext {}
+
+impl ConsumerContext for SegmentConsumerContext {
+    fn pre_rebalance(&self, rebalance: &Rebalance) {
+        info!("Pre rebalance {:?}", rebalance);
+
+        // partitions are unassigned on errors too
+        if let Rebalance::Revoke | Rebalance::Error(_) = rebalance {
+            self.revoke();
+        }
+    }
+
+    fn post_rebalance(&self, rebalance: &Rebalance) {
+        info!("Post rebalance {:?}", rebalance);
+    }
+
+    fn commit_callback(&self, result: KafkaResult<()>, _offsets: &TopicPartitionList) {
+        info!("Committing offsets: {:?}", result);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use clap::ValueEnum;
+
+use rdkafka::consumer::{Consumer, ConsumerContext};
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+pub const SEEK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub type PartitionKey = (String, i32);
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OffsetReset {
+    Earliest,
+    Latest,
+}
+
+impl OffsetReset {
+    // value of auto.offset.reset
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OffsetReset::Earliest => "earliest",
+            OffsetReset::Latest => "latest",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionOffset {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+// Where the group starts, committed for it before it joins
+#[derive(Debug, Clone)]
+pub enum StartPosition {
+    Committed,
+    Earliest,
+    Latest,
+    // first segments produced at or after the time
+    Time(DateTime<Utc>),
+    // only the given partitions are moved
+    Offsets(Vec<PartitionOffset>),
+}
+
+// Messages sent in the window are delivered again, bounds included
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayWindow {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl ReplayWindow {
+    pub fn contains(&self, send_time: &str) -> bool {
+        send_time
+            .parse::<i64>()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+            .is_some_and(|t| self.from <= t && t <= self.to)
+    }
+}
+
+pub fn start_offsets<C: ConsumerContext, K: Consumer<C>>(
+    consumer: &K,
+    partitions: &[PartitionKey],
+    start: &StartPosition,
+) -> Result<TopicPartitionList, Error> {
+    let mut list = TopicPartitionList::new();
+
+    let offsets: Vec<(PartitionKey, i64)> = match start {
+        StartPosition::Committed => vec![],
+        StartPosition::Earliest | StartPosition::Latest => partitions
+            .iter()
+            .map(|(topic, partition)| {
+                let (low, high) = consumer.fetch_watermarks(topic, *partition, SEEK_TIMEOUT)?;
+                let offset = match start {
+                    StartPosition::Earliest => low,
+                    _ => high,
+                };
+                Ok(((topic.clone(), *partition), offset))
+            })
+            .collect::<Result<_, Error>>()?,
+        StartPosition::Time(time) => offsets_for_time(consumer, partitions, *time)?
+            .into_iter()
+            .collect(),
+        StartPosition::Offsets(offsets) => offsets
+            .iter()
+            .map(|o| {
+                let key = (o.topic.clone(), o.partition);
+                match partitions.contains(&key) {
+                    true => Ok((key, o.offset)),
+                    false => Err(anyhow!(
+                        "{}:{} is not a consumed partition",
+                        o.topic,
+                        o.partition
+                    )),
+                }
+            })
+            .collect::<Result<_, Error>>()?,
+    };
+
+    for ((topic, partition), offset) in offsets {
+        list.add_partition_offset(&topic, partition, Offset::Offset(offset))?;
+    }
+
+    Ok(list)
+}
+
+// Members of the group running now
+pub fn group_members<C: ConsumerContext, K: Consumer<C>>(
+    consumer: &K,
+    group_id: &str,
+) -> Result<usize, Error> {
+    let groups = consumer.fetch_group_list(Some(group_id), SEEK_TIMEOUT)?;
+
+    Ok(groups
+        .groups()
+        .iter()
+        .filter(|g| g.name() == group_id)
+        .map(|g| g.members().len())
+        .sum())
+}
+
+// Start offsets to assign and end offsets (exclusive) to stop at. The end is taken `tail`
+// after the window, so segments produced late still complete the messages of the window.
+// Partitions without segments in the range are left out.
+pub fn replay_offsets<C: ConsumerContext, K: Consumer<C>>(
+    consumer: &K,
+    partitions: &[PartitionKey],
+    window: &ReplayWindow,
+    tail: Duration,
+) -> Result<(TopicPartitionList, HashMap<PartitionKey, i64>), Error> {
+    let starts = offsets_for_time(consumer, partitions, window.from)?;
+    let ends = offsets_for_time(consumer, partitions, window.to + tail)?;
+
+    let mut list = TopicPartitionList::new();
+    let mut end_offsets = HashMap::new();
+
+    for (key, start) in starts {
+        let end = ends.get(&key).copied().unwrap_or(start);
+        if start >= end {
+            continue;
+        }
+        list.add_partition_offset(&key.0, key.1, Offset::Offset(start))?;
+        end_offsets.insert(key, end);
+    }
+
+    Ok((list, end_offsets))
+}
+
+// How far the replayed partitions are consumed. A partition is done once the next offset to
+// fetch reaches its end, or when it stopped moving and nothing is left to fetch before the end,
+// e.g. its last offsets are transaction markers or were compacted away.
+pub struct ReplayProgress {
+    end_offsets: HashMap<PartitionKey, i64>,
+    // the start offset until something is consumed
+    next_offsets: HashMap<PartitionKey, i64>,
+    // next offsets at the previous check
+    checked: HashMap<PartitionKey, i64>,
+}
+
+impl ReplayProgress {
+    pub fn new(
+        starts: HashMap<PartitionKey, i64>,
+        end_offsets: HashMap<PartitionKey, i64>,
+    ) -> Self {
+        Self {
+            end_offsets,
+            next_offsets: starts,
+            checked: HashMap::new(),
+        }
+    }
+
+    pub fn end(&self, partition: &PartitionKey) -> Option<i64> {
+        self.end_offsets.get(partition).copied()
+    }
+
+    pub fn consumed(&mut self, partition: &PartitionKey, offset: i64) {
+        self.advance(partition, offset + 1);
+    }
+
+    // position of the consumer, Invalid before the first fetch keeps the start offset
+    pub fn positioned(&mut self, partition: &PartitionKey, position: Offset) {
+        if let Offset::Offset(offset) = position {
+            self.advance(partition, offset);
+        }
+    }
+
+    // high_watermark is asked only for partitions which haven't moved since the previous check
+    pub fn finished(&mut self, high_watermark: impl Fn(&PartitionKey) -> Option<i64>) -> bool {
+        let mut finished = true;
+
+        for (partition, end) in self.end_offsets.iter() {
+            let next = self.next_offsets.get(partition).copied().unwrap_or(0);
+            if next >= *end {
+                continue;
+            }
+
+            let stalled = self.checked.insert(partition.clone(), next) == Some(next);
+            match stalled.then(|| high_watermark(partition)).flatten() {
+                Some(high) if high <= next => {
+                    self.next_offsets.insert(partition.clone(), *end);
+                }
+                _ => finished = false,
+            }
+        }
+
+        finished
+    }
+
+    fn advance(&mut self, partition: &PartitionKey, next: i64) {
+        let current = self.next_offsets.entry(partition.clone()).or_insert(next);
+        *current = (*current).max(next);
+    }
+}
+
+// Offsets of the first segments produced at or after the time,
+// the end of the partition when there are none yet
+fn offsets_for_time<C: ConsumerContext, K: Consumer<C>>(
+    consumer: &K,
+    partitions: &[PartitionKey],
+    time: DateTime<Utc>,
+) -> Result<HashMap<PartitionKey, i64>, Error> {
+    let mut request = TopicPartitionList::new();
+    for (topic, partition) in partitions {
+        request.add_partition_offset(topic, *partition, Offset::Offset(time.timestamp_millis()))?;
+    }
+
+    let found = consumer.offsets_for_times(request, SEEK_TIMEOUT)?;
+
+    found
+        .elements()
+        .iter()
+        .map(|e| {
+            let offset = match e.offset() {
+                Offset::Offset(offset) => offset,
+                _ => {
+                    consumer
+                        .fetch_watermarks(e.topic(), e.partition(), SEEK_TIMEOUT)?
+                        .1
+                }
+            };
+            Ok(((e.topic().to_owned(), e.partition()), offset))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partition(n: i32) -> PartitionKey {
+        ("topic".to_owned(), n)
+    }
+
+    fn progress(ranges: &[(i32, i64, i64)]) -> ReplayProgress {
+        let starts = ranges.iter().map(|(p, start, _)| (partition(*p), *start));
+        let ends = ranges.iter().map(|(p, _, end)| (partition(*p), *end));
+        ReplayProgress::new(starts.collect(), ends.collect())
+    }
+
+    #[test]
+    fn finished_when_every_partition_reaches_its_end() {
+        let mut progress = progress(&[(0, 0, 10), (1, 5, 8)]);
+        let no_watermark = |_: &PartitionKey| None;
+
+        progress.consumed(&partition(0), 9);
+        assert!(!progress.finished(no_watermark));
+
+        progress.positioned(&partition(1), Offset::Offset(8));
+        assert!(progress.finished(no_watermark));
+    }
+
+    #[test]
+    fn segment_after_the_end_finishes_the_partition() {
+        let mut progress = progress(&[(0, 0, 10)]);
+
+        // offset 9 is a transaction marker, the next segment is past the end
+        progress.consumed(&partition(0), 8);
+        progress.consumed(&partition(0), 12);
+
+        assert!(progress.finished(|_| None));
+    }
+
+    #[test]
+    fn stalled_partition_finishes_at_the_high_watermark() {
+        let mut progress = progress(&[(0, 0, 10)]);
+        progress.consumed(&partition(0), 8);
+
+        // the first check only notes where the partition is
+        assert!(!progress.finished(|_| panic!("watermark of a moving partition")));
+        // the last offset before the end is not fetchable
+        assert!(!progress.finished(|_| Some(11)));
+        progress.consumed(&partition(0), 8);
+        assert!(progress.finished(|_| Some(9)));
+    }
+
+    #[test]
+    fn invalid_position_is_the_start_offset() {
+        let mut progress = progress(&[(0, 4, 10)]);
+
+        progress.positioned(&partition(0), Offset::Invalid);
+        assert!(!progress.finished(|_| Some(4)));
+        // nothing was ever fetchable from the start
+        assert!(progress.finished(|_| Some(4)));
+    }
+
+    #[test]
+    fn window_contains_its_bounds() {
+        let window = ReplayWindow {
+            from: DateTime::from_timestamp_millis(1_000).unwrap(),
+            to: DateTime::from_timestamp_millis(2_000).unwrap(),
+        };
+
+        assert!(window.contains("1000"));
+        assert!(window.contains("2000"));
+        assert!(!window.contains("999"));
+        assert!(!window.contains("2001"));
+        assert!(!window.contains("not a time"));
+    }
+}
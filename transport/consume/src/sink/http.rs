@@ -0,0 +1,132 @@
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use reqwest::{Client, IntoUrl, StatusCode, Url};
+
+use super::{MessageSink, SendError, SinkKind};
+use crate::consumer::Message;
+
+// POSTs the message as json to the receive service
+pub struct HttpSink {
+    client: Client,
+    url: Url,
+    target: String,
+}
+
+impl HttpSink {
+    pub fn new(receive_url: impl IntoUrl) -> Result<Self, Error> {
+        let url = receive_url.into_url()?;
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            target: url.to_string(),
+            url,
+        })
+    }
+}
+
+#[async_trait]
+impl MessageSink for HttpSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Http
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    // 2xx is success, 5xx and 429 are retried, other statuses are permanent failures
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .json(&message)
+            .send()
+            .await
+            .map_err(SendError::retryable)?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        Err(SendError {
+            status: Some(status),
+            retryable: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            reason: anyhow!("receive service answered {}", status),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use warp::http::StatusCode as WarpStatus;
+    use warp::Filter;
+
+    use super::*;
+
+    // receive service answering every post with the status
+    fn receive_service(status: u16) -> SocketAddr {
+        let route = warp::post()
+            .map(move || warp::reply::with_status("", WarpStatus::from_u16(status).unwrap()));
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    fn message() -> Message {
+        Message {
+            payload: "message".to_owned(),
+            has_error: false,
+            sender: "sender".to_owned(),
+            send_time: "1700000000000".to_owned(),
+            error: None,
+            partial: false,
+            gaps: vec![],
+        }
+    }
+
+    async fn send(status: u16) -> Result<(), SendError> {
+        let addr = receive_service(status);
+        let sink = HttpSink::new(format!("http://{}/receive", addr)).unwrap();
+        sink.send(&message()).await
+    }
+
+    #[tokio::test]
+    async fn success_status_is_delivered() {
+        assert!(send(200).await.is_ok());
+        assert!(send(204).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_throttling_are_retryable() {
+        for status in [500, 503, 429] {
+            let e = send(status).await.err().unwrap();
+            assert!(e.retryable, "{}", status);
+            assert_eq!(e.status.map(|s| s.as_u16()), Some(status));
+        }
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_permanent() {
+        for status in [400, 404, 413] {
+            let e = send(status).await.err().unwrap();
+            assert!(!e.retryable, "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_service_is_retryable() {
+        // the port is free once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let sink = HttpSink::new(format!("http://{}/receive", addr)).unwrap();
+
+        let e = sink.send(&message()).await.err().unwrap();
+        assert!(e.retryable);
+        assert!(e.status.is_none());
+    }
+}
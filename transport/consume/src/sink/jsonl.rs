@@ -0,0 +1,113 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::info;
+
+use super::{MessageSink, SendError, SinkKind};
+use crate::consumer::Message;
+
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    // the file is rotated before a line would make it larger
+    pub max_bytes: u64,
+    // rotated files kept as <path>.1 (newest) .. <path>.<max_files>
+    pub max_files: usize,
+}
+
+struct JsonlFile {
+    file: File,
+    len: u64,
+}
+
+// Appends every message as a json line to the file
+pub struct JsonlSink {
+    file: Mutex<JsonlFile>,
+    path: PathBuf,
+    target: String,
+    rotation: Rotation,
+}
+
+impl JsonlSink {
+    pub fn new(path: impl AsRef<Path>, rotation: Rotation) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = open_append(&path)?;
+        let len = file.metadata()?.len();
+
+        Ok(Self {
+            file: Mutex::new(JsonlFile { file, len }),
+            target: path.display().to_string(),
+            path,
+            rotation,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&self, current: &mut JsonlFile) -> Result<()> {
+        let oldest = self.rotated_path(self.rotation.max_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for n in (1..self.rotation.max_files).rev() {
+            let rotated = self.rotated_path(n);
+            if rotated.exists() {
+                fs::rename(&rotated, self.rotated_path(n + 1))?;
+            }
+        }
+
+        if self.rotation.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        current.file = open_append(&self.path)?;
+        current.len = 0;
+
+        info!("rotated {}", self.target);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageSink for JsonlSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Jsonl
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        let mut line = serde_json::to_vec(message).map_err(SendError::permanent)?;
+        line.push(b'\n');
+
+        let mut current = self.file.lock().unwrap();
+
+        if current.len > 0 && current.len + line.len() as u64 > self.rotation.max_bytes {
+            self.rotate(&mut current).map_err(SendError::retryable)?;
+        }
+
+        current
+            .file
+            .write_all(&line)
+            .map_err(SendError::retryable)?;
+        current.len += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use super::{MessageSink, SendError, SinkKind};
+use crate::consumer::Message;
+
+// Produces the message as json to the output topic, keyed by sender
+// so messages of a sender keep their order
+pub struct KafkaSink {
+    base: FutureProducer,
+    topic: String,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str) -> Self {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("Output producer creation error");
+
+        Self {
+            base: producer,
+            topic: topic.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSink for KafkaSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Kafka
+    }
+
+    fn target(&self) -> &str {
+        &self.topic
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        let payload = serde_json::to_vec(message).map_err(SendError::permanent)?;
+
+        let record = FutureRecord::to(&self.topic)
+            .payload(&payload)
+            .key(&message.sender);
+
+        self.base
+            .send(record, Duration::from_secs(0))
+            .await
+            .map_err(|e| SendError::retryable(anyhow!(e.0)))?;
+
+        Ok(())
+    }
+}
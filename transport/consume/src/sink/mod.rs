@@ -0,0 +1,126 @@
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use reqwest::StatusCode;
+
+use crate::consumer::Message;
+
+mod http;
+mod jsonl;
+mod kafka;
+mod routed;
+mod stdout;
+mod unix;
+
+pub use http::HttpSink;
+pub use jsonl::{JsonlSink, Rotation};
+pub use kafka::KafkaSink;
+pub use routed::RoutedHttpSink;
+pub use stdout::StdoutSink;
+pub use unix::UnixSocketSink;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SinkKind {
+    Http,
+    Routed,
+    Kafka,
+    Jsonl,
+    Stdout,
+    Unix,
+    Subscribe,
+}
+
+impl SinkKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SinkKind::Http => "http",
+            SinkKind::Routed => "routed",
+            SinkKind::Kafka => "kafka",
+            SinkKind::Jsonl => "jsonl",
+            SinkKind::Stdout => "stdout",
+            SinkKind::Unix => "unix",
+            SinkKind::Subscribe => "subscribe",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guarantee {
+    // retried with backoff, dead-lettered when retries run out or the failure is permanent
+    AtLeastOnce,
+    // sent once, a failure is logged and the message is dropped for this sink
+    BestEffort,
+}
+
+pub struct SendError {
+    // answer of the receive service, only http sinks have it
+    pub status: Option<StatusCode>,
+    pub retryable: bool,
+    pub reason: Error,
+}
+
+impl SendError {
+    pub fn retryable(reason: impl Into<Error>) -> Self {
+        Self {
+            status: None,
+            retryable: true,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn permanent(reason: impl Into<Error>) -> Self {
+        Self {
+            status: None,
+            retryable: false,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} ({})", self.reason, status),
+            None => write!(f, "{}", self.reason),
+        }
+    }
+}
+
+// Destination of reassembled messages. A send either stores the whole message or fails,
+// retries are done by Delivery according to the guarantee of the sink.
+#[async_trait]
+pub trait MessageSink: Send + Sync {
+    fn kind(&self) -> SinkKind;
+
+    // where messages go, e.g. url or file path, for logs and dead-letter headers
+    fn target(&self) -> &str;
+
+    async fn send(&self, message: &Message) -> Result<(), SendError>;
+
+    // the message won't be sent again, e.g. it is dead-lettered,
+    // sinks keeping a partial progress of sends drop it
+    fn forget(&self, _message: &Message) {}
+}
+
+// a sink shared with other parts, e.g. the subscription hub with the server
+#[async_trait]
+impl<T: MessageSink + ?Sized> MessageSink for Arc<T> {
+    fn kind(&self) -> SinkKind {
+        (**self).kind()
+    }
+
+    fn target(&self) -> &str {
+        (**self).target()
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        (**self).send(message).await
+    }
+
+    fn forget(&self, message: &Message) {
+        (**self).forget(message)
+    }
+}
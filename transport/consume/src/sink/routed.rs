@@ -0,0 +1,373 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{debug, info, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+
+use common::pattern::Pattern;
+
+use super::{MessageSink, SendError, SinkKind};
+use crate::consumer::Message;
+
+#[derive(Debug, Deserialize)]
+struct EndpointDef {
+    url: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    // sent as `Authorization: Bearer <token>`
+    token: Option<String>,
+    // environment variable holding the token, keeps secrets out of the file
+    token_env: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteDef {
+    sender: Pattern,
+    endpoints: Vec<EndpointDef>,
+}
+
+// {"routes": [{"sender": {"glob": "team-*"}, "endpoints": [{"url": "http://team/receive",
+//   "headers": {"x-source": "transport"}, "token_env": "TEAM_TOKEN"}]}],
+//  "default": [{"url": "http://all/receive"}]}
+#[derive(Debug, Deserialize)]
+struct RoutesFile {
+    #[serde(default)]
+    routes: Vec<RouteDef>,
+    // endpoints of messages not matched by any route, they are dropped without it
+    #[serde(default)]
+    default: Vec<EndpointDef>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: Url,
+    headers: HeaderMap,
+}
+
+impl TryFrom<EndpointDef> for Endpoint {
+    type Error = anyhow::Error;
+
+    fn try_from(def: EndpointDef) -> Result<Self> {
+        let url = Url::parse(&def.url)?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in def.headers.iter() {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+
+        let token = match (def.token, def.token_env) {
+            (Some(token), _) => Some(token),
+            (None, Some(var)) => Some(
+                std::env::var(&var).map_err(|e| anyhow!("token_env {} of {}: {}", var, url, e))?,
+            ),
+            (None, None) => None,
+        };
+        if let Some(token) = token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        Ok(Self { url, headers })
+    }
+}
+
+#[derive(Debug)]
+struct Route {
+    sender: Pattern,
+    endpoints: Vec<Endpoint>,
+}
+
+// Routes are checked in the file order, the first one matching the sender is chosen
+#[derive(Debug)]
+struct RouteTable {
+    routes: Vec<Route>,
+    default: Vec<Endpoint>,
+}
+
+impl RouteTable {
+    fn load(path: &Path) -> Result<Self> {
+        let file: RoutesFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        let routes = file
+            .routes
+            .into_iter()
+            .map(|r| {
+                Ok(Route {
+                    sender: r.sender,
+                    endpoints: endpoints(r.endpoints)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            routes,
+            default: endpoints(file.default)?,
+        })
+    }
+
+    fn endpoints(&self, sender: &str) -> &[Endpoint] {
+        self.routes
+            .iter()
+            .find(|r| r.sender.matches(sender))
+            .map(|r| r.endpoints.as_slice())
+            .unwrap_or(&self.default)
+    }
+}
+
+fn endpoints(defs: Vec<EndpointDef>) -> Result<Vec<Endpoint>> {
+    defs.into_iter().map(Endpoint::try_from).collect()
+}
+
+// POSTs the message as json to every endpoint of the route of its sender.
+// The routes file is reloaded when its mtime changes, a broken file keeps the previous routes.
+// A send fails if any endpoint fails, a retry posts only to the endpoints which haven't
+// accepted the message yet.
+pub struct RoutedHttpSink {
+    client: Client,
+    table: Arc<RwLock<Arc<RouteTable>>>,
+    target: String,
+    // endpoints which accepted a message whose send failed on others, by sender and send_time
+    accepted: Mutex<HashMap<(String, String), HashSet<Url>>>,
+}
+
+impl RoutedHttpSink {
+    pub fn new(path: impl Into<PathBuf>, reload_interval: Duration) -> Result<Self> {
+        let path = path.into();
+        let mtime = fs::metadata(&path)?.modified()?;
+        let table = Arc::new(RwLock::new(Arc::new(RouteTable::load(&path)?)));
+
+        info!("loaded routes from {:?}", path);
+
+        tokio::spawn(reload(path.clone(), table.clone(), mtime, reload_interval));
+
+        Ok(Self {
+            client: Client::new(),
+            table,
+            target: path.display().to_string(),
+            accepted: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn post(&self, endpoint: &Endpoint, message: &Message) -> Result<(), SendError> {
+        let response = self
+            .client
+            .post(endpoint.url.clone())
+            .headers(endpoint.headers.clone())
+            .json(message)
+            .send()
+            .await
+            .map_err(|e| SendError::retryable(anyhow!("{}: {}", endpoint.url, e)))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        Err(SendError {
+            status: Some(status),
+            retryable: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            reason: anyhow!("{} answered {}", endpoint.url, status),
+        })
+    }
+}
+
+#[async_trait]
+impl MessageSink for RoutedHttpSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Routed
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        // a reload during the send doesn't change the endpoints of this message
+        let table = self.table.read().unwrap().clone();
+
+        let endpoints = table.endpoints(&message.sender);
+        if endpoints.is_empty() {
+            debug!(
+                "no route for message {}/{}",
+                message.sender, message.send_time
+            );
+            return Ok(());
+        }
+
+        let key = (message.sender.clone(), message.send_time.clone());
+        let accepted = self
+            .accepted
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .unwrap_or_default();
+        let remaining: Vec<&Endpoint> = endpoints
+            .iter()
+            .filter(|e| !accepted.contains(&e.url))
+            .collect();
+
+        let results = join_all(remaining.iter().map(|e| self.post(e, message))).await;
+
+        let mut errors = vec![];
+        let mut newly_accepted = vec![];
+        for (endpoint, result) in remaining.into_iter().zip(results) {
+            match result {
+                Ok(_) => newly_accepted.push(endpoint.url.clone()),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        let mut progress = self.accepted.lock().unwrap();
+        if errors.is_empty() {
+            progress.remove(&key);
+            return Ok(());
+        }
+        progress.entry(key).or_default().extend(newly_accepted);
+
+        // retried while any endpoint may still accept it
+        let retryable = errors.iter().any(|e| e.retryable);
+        let reason = errors
+            .iter()
+            .map(|e| e.reason.to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        Err(SendError {
+            status: errors[0].status,
+            retryable,
+            reason: anyhow!(reason),
+        })
+    }
+
+    fn forget(&self, message: &Message) {
+        let key = (message.sender.clone(), message.send_time.clone());
+        self.accepted.lock().unwrap().remove(&key);
+    }
+}
+
+async fn reload(
+    path: PathBuf,
+    table: Arc<RwLock<Arc<RouteTable>>>,
+    mut loaded_mtime: SystemTime,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        let mtime = match fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(mtime) => mtime,
+            Err(e) => {
+                warn!("failed to stat routes file {:?}: {}", path, e);
+                continue;
+            }
+        };
+        if mtime == loaded_mtime {
+            continue;
+        }
+        loaded_mtime = mtime;
+
+        match RouteTable::load(&path) {
+            Ok(loaded) => {
+                *table.write().unwrap() = Arc::new(loaded);
+                info!("reloaded routes from {:?}", path);
+            }
+            Err(e) => warn!(
+                "failed to reload routes from {:?}, keeping the previous ones: {}",
+                path, e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use warp::http::StatusCode as WarpStatus;
+    use warp::Filter;
+
+    use super::*;
+
+    // receive service answering with the statuses in order, then with 200
+    fn endpoint(statuses: Vec<u16>) -> (SocketAddr, Arc<AtomicUsize>) {
+        let posts = Arc::new(AtomicUsize::new(0));
+        let counter = posts.clone();
+        let route = warp::post().map(move || {
+            let n = counter.fetch_add(1, Ordering::Relaxed);
+            let status = statuses.get(n).copied().unwrap_or(200);
+            warp::reply::with_status("", WarpStatus::from_u16(status).unwrap())
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, posts)
+    }
+
+    fn sink(name: &str, addrs: &[SocketAddr]) -> RoutedHttpSink {
+        let endpoints: Vec<String> = addrs
+            .iter()
+            .map(|a| format!("{{\"url\": \"http://{}/receive\"}}", a))
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("routes-{}-{}.json", name, std::process::id()));
+        fs::write(&path, format!("{{\"default\": [{}]}}", endpoints.join(","))).unwrap();
+
+        RoutedHttpSink::new(path, Duration::from_secs(3600)).unwrap()
+    }
+
+    fn message() -> Message {
+        Message {
+            payload: "message".to_owned(),
+            has_error: false,
+            sender: "sender".to_owned(),
+            send_time: "1700000000000".to_owned(),
+            error: None,
+            partial: false,
+            gaps: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_posts_only_to_failed_endpoints() {
+        let (ok, ok_posts) = endpoint(vec![]);
+        let (failing, failing_posts) = endpoint(vec![503]);
+        let sink = sink("retry", &[ok, failing]);
+
+        let e = sink.send(&message()).await.err().unwrap();
+        assert!(e.retryable);
+        assert!(sink.send(&message()).await.is_ok());
+
+        assert_eq!(ok_posts.load(Ordering::Relaxed), 1);
+        assert_eq!(failing_posts.load(Ordering::Relaxed), 2);
+        assert!(sink.accepted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forgotten_message_is_posted_to_every_endpoint_again() {
+        let (ok, ok_posts) = endpoint(vec![]);
+        let (failing, _) = endpoint(vec![400]);
+        let sink = sink("forget", &[ok, failing]);
+
+        let e = sink.send(&message()).await.err().unwrap();
+        assert!(!e.retryable);
+        sink.forget(&message());
+        assert!(sink.accepted.lock().unwrap().is_empty());
+
+        assert!(sink.send(&message()).await.is_ok());
+        assert_eq!(ok_posts.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,31 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+
+use super::{MessageSink, SendError, SinkKind};
+use crate::consumer::Message;
+
+// Prints every message as a json line, logs go to stderr so they don't mix
+pub struct StdoutSink;
+
+#[async_trait]
+impl MessageSink for StdoutSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Stdout
+    }
+
+    fn target(&self) -> &str {
+        "stdout"
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        let mut line = serde_json::to_vec(message).map_err(SendError::permanent)?;
+        line.push(b'\n');
+
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&line).map_err(SendError::retryable)?;
+        stdout.flush().map_err(SendError::retryable)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+
+use super::{MessageSink, SendError, SinkKind};
+use crate::consumer::Message;
+
+// Writes every message as a json line to the listener of a unix domain socket.
+// The connection is opened on the first send and reopened after a failed write.
+pub struct UnixSocketSink {
+    path: PathBuf,
+    target: String,
+    stream: Mutex<Option<UnixStream>>,
+}
+
+impl UnixSocketSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+
+        Self {
+            target: path.display().to_string(),
+            path,
+            stream: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSink for UnixSocketSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Unix
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        let mut line = serde_json::to_vec(message).map_err(SendError::permanent)?;
+        line.push(b'\n');
+
+        let mut stream = self.stream.lock().await;
+
+        let connected = match stream.as_mut() {
+            Some(connected) => connected,
+            None => stream.insert(
+                UnixStream::connect(&self.path)
+                    .await
+                    .map_err(SendError::retryable)?,
+            ),
+        };
+
+        // a half written line can't be continued on a new connection
+        if let Err(e) = connected.write_all(&line).await {
+            *stream = None;
+            return Err(SendError::retryable(e));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use common::SegmentWithTime;
+
+const LOG_FILE_NAME: &str = "pending.log";
+const COMPACT_FILE_NAME: &str = "pending.log.compact";
+// journal is rewritten with only the pending segments after this many writes
+const COMPACT_AFTER_ENTRIES: usize = 10_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StateEntry {
+    Add { segment: SegmentWithTime },
+    // message of the sender with this send_time is delivered or expired
+    Finish { sender: String, send_time: String },
+}
+
+struct Journal {
+    log: File,
+    entries: usize,
+    // written since the last sync
+    dirty: bool,
+}
+
+// Journal of the segments cache in the state directory: json lines of added segments
+// and finished messages. Replaying it on startup gives the segments of pending messages.
+// Writes reach the disk on sync, which the consumer calls before committing offsets.
+pub struct StateStore {
+    journal: Mutex<Journal>,
+    log_path: PathBuf,
+    compact_path: PathBuf,
+}
+
+impl fmt::Debug for StateStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StateStore")
+            .field("log_path", &self.log_path)
+            .finish()
+    }
+}
+
+impl StateStore {
+    pub fn open(dir: impl AsRef<Path>) -> Result<(Self, Vec<SegmentWithTime>)> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let log_path = dir.join(LOG_FILE_NAME);
+        let compact_path = dir.join(COMPACT_FILE_NAME);
+
+        let mut pending: HashMap<(String, String), Vec<SegmentWithTime>> = HashMap::new();
+        let mut entries = 0;
+        // length of the journal up to its last complete line
+        let mut complete_len = 0;
+
+        if log_path.exists() {
+            let mut reader = BufReader::new(File::open(&log_path)?);
+            let mut line = vec![];
+            loop {
+                line.clear();
+                let read = reader.read_until(b'\n', &mut line)?;
+                // the last line may be cut by a crash in the middle of a write
+                if read == 0 || line.last() != Some(&b'\n') {
+                    break;
+                }
+                complete_len += read as u64;
+                entries += 1;
+
+                let entry: StateEntry = match serde_json::from_slice(&line) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("skipping broken state entry: {}", e);
+                        continue;
+                    }
+                };
+
+                match entry {
+                    StateEntry::Add { segment } => {
+                        pending
+                            .entry((segment.segment.sender.clone(), segment.send_time.clone()))
+                            .or_default()
+                            .push(segment);
+                    }
+                    StateEntry::Finish { sender, send_time } => {
+                        pending.remove(&(sender, send_time));
+                    }
+                }
+            }
+        }
+
+        let restored: Vec<SegmentWithTime> = pending.into_values().flatten().collect();
+
+        info!(
+            "state opened in {:?}: {} pending segments restored",
+            dir,
+            restored.len()
+        );
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        if log.metadata()?.len() > complete_len {
+            warn!("dropping the cut last line of the state journal");
+            log.set_len(complete_len)?;
+            log.sync_data()?;
+        }
+
+        Ok((
+            Self {
+                journal: Mutex::new(Journal {
+                    log,
+                    entries,
+                    dirty: false,
+                }),
+                log_path,
+                compact_path,
+            },
+            restored,
+        ))
+    }
+
+    pub fn add(&self, segment: &SegmentWithTime) -> Result<()> {
+        self.append(&StateEntry::Add {
+            segment: segment.clone(),
+        })
+    }
+
+    pub fn finish(&self, sender: &str, send_time: &str) -> Result<()> {
+        self.append(&StateEntry::Finish {
+            sender: sender.to_owned(),
+            send_time: send_time.to_owned(),
+        })
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.journal.lock().unwrap().entries >= COMPACT_AFTER_ENTRIES
+    }
+
+    // Replaces the journal with the given pending segments
+    pub fn compact<'a>(&self, pending: impl Iterator<Item = &'a SegmentWithTime>) -> Result<()> {
+        let mut journal = self.journal.lock().unwrap();
+
+        let mut writer = BufWriter::new(File::create(&self.compact_path)?);
+        let mut entries = 0;
+
+        for segment in pending {
+            let entry = StateEntry::Add {
+                segment: segment.clone(),
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+            entries += 1;
+        }
+
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&self.compact_path, &self.log_path)?;
+
+        journal.log = OpenOptions::new().append(true).open(&self.log_path)?;
+        journal.entries = entries;
+        journal.dirty = false;
+
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        let mut journal = self.journal.lock().unwrap();
+        if journal.dirty {
+            journal.log.sync_data()?;
+            journal.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    fn append(&self, entry: &StateEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut journal = self.journal.lock().unwrap();
+        journal.log.write_all(&line)?;
+        journal.entries += 1;
+        journal.dirty = true;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Segment;
+
+    use super::*;
+
+    fn segment(send_time: &str, seg_num: usize) -> SegmentWithTime {
+        SegmentWithTime {
+            segment: Segment {
+                payload: vec![b'a', 0],
+                seg_count: 3,
+                seg_num,
+                sender: "sender".to_owned(),
+            },
+            send_time: send_time.to_owned(),
+        }
+    }
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("state-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn seg_nums(restored: &[SegmentWithTime]) -> Vec<usize> {
+        let mut nums: Vec<usize> = restored.iter().map(|s| s.segment.seg_num).collect();
+        nums.sort();
+        nums
+    }
+
+    #[test]
+    fn finished_messages_are_not_restored() {
+        let dir = state_dir("restore");
+        let (state, restored) = StateStore::open(&dir).unwrap();
+        assert!(restored.is_empty());
+
+        state.add(&segment("1", 0)).unwrap();
+        state.add(&segment("2", 0)).unwrap();
+        state.add(&segment("2", 1)).unwrap();
+        state.finish("sender", "1").unwrap();
+        state.sync().unwrap();
+        drop(state);
+
+        let (_, restored) = StateStore::open(&dir).unwrap();
+        assert!(restored.iter().all(|s| s.send_time == "2"));
+        assert_eq!(seg_nums(&restored), vec![0, 1]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cut_line_does_not_swallow_the_next_entry() {
+        let dir = state_dir("cut-line");
+        let (state, _) = StateStore::open(&dir).unwrap();
+        state.add(&segment("1", 0)).unwrap();
+        drop(state);
+
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE_NAME))
+            .unwrap();
+        log.write_all(b"{\"add\":{\"segm").unwrap();
+        drop(log);
+
+        let (state, restored) = StateStore::open(&dir).unwrap();
+        assert_eq!(seg_nums(&restored), vec![0]);
+        state.add(&segment("1", 1)).unwrap();
+        drop(state);
+
+        let (_, restored) = StateStore::open(&dir).unwrap();
+        assert_eq!(seg_nums(&restored), vec![0, 1]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_only_the_given_segments() {
+        let dir = state_dir("compact");
+        let (state, _) = StateStore::open(&dir).unwrap();
+        for seg_num in 0..3 {
+            state.add(&segment("1", seg_num)).unwrap();
+        }
+        state.finish("sender", "1").unwrap();
+        state.add(&segment("2", 0)).unwrap();
+
+        let pending = [segment("2", 0)];
+        state.compact(pending.iter()).unwrap();
+        assert!(!state.needs_compaction());
+        state.add(&segment("2", 1)).unwrap();
+        drop(state);
+
+        let log = fs::read_to_string(dir.join(LOG_FILE_NAME)).unwrap();
+        assert_eq!(log.lines().count(), 2);
+
+        let (_, restored) = StateStore::open(&dir).unwrap();
+        assert_eq!(seg_nums(&restored), vec![0, 1]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
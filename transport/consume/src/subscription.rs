@@ -0,0 +1,375 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use clap::ValueEnum;
+use futures::{stream, Stream, StreamExt};
+use log::warn;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::wrappers::ReceiverStream;
+
+use common::pattern::Pattern;
+
+use crate::consumer::Message;
+use crate::sink::{MessageSink, SendError, SinkKind};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SlowClientPolicy {
+    // close the stream, the client resumes from its cursor while the history has it
+    Disconnect,
+    // drop messages for the client and tell it which ones it lost
+    Skip,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscriptionLimits {
+    // delivered messages kept for resuming clients
+    pub history: usize,
+    // messages buffered for a client before the slow client policy applies
+    pub buffer: usize,
+    pub slow_client: SlowClientPolicy,
+}
+
+// Position of a message in the stream, `<epoch>-<seq>`. The epoch changes on restart,
+// a cursor of another epoch resumes from the start of the history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    epoch: i64,
+    seq: u64,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (epoch, seq) = s
+            .split_once('-')
+            .ok_or_else(|| format!("cursor {:?} is not <epoch>-<seq>", s))?;
+
+        Ok(Self {
+            epoch: epoch.parse().map_err(|e| format!("cursor epoch: {}", e))?,
+            seq: seq.parse().map_err(|e| format!("cursor seq: {}", e))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    // message serialized once for all the clients
+    Message { cursor: Cursor, json: Arc<String> },
+    // sequence numbers of the epoch the client didn't get
+    Lost { from: u64, to: u64 },
+}
+
+struct Entry {
+    seq: u64,
+    sender: String,
+    json: Arc<String>,
+}
+
+struct Subscriber {
+    sender: Option<Pattern>,
+    tx: mpsc::Sender<Event>,
+    lost: Option<(u64, u64)>,
+}
+
+fn sender_matches(pattern: &Option<Pattern>, sender: &str) -> bool {
+    match pattern {
+        Some(pattern) => pattern.matches(sender),
+        None => true,
+    }
+}
+
+impl Subscriber {
+    // returns false when the subscriber has to be dropped
+    fn push(&mut self, seq: u64, event: Event, policy: SlowClientPolicy) -> bool {
+        if let Some((from, to)) = self.lost {
+            match self.tx.try_send(Event::Lost { from, to }) {
+                Ok(_) => self.lost = None,
+                Err(TrySendError::Full(_)) => {
+                    self.lost = Some((from, seq));
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+
+        match self.tx.try_send(event) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => match policy {
+                SlowClientPolicy::Disconnect => {
+                    warn!("disconnecting slow subscriber at message {}", seq);
+                    false
+                }
+                SlowClientPolicy::Skip => {
+                    self.lost = Some((seq, seq));
+                    true
+                }
+            },
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+struct HubState {
+    next_seq: u64,
+    history: VecDeque<Entry>,
+    subscribers: Vec<Subscriber>,
+}
+
+// Fans delivered messages out to the clients of GET /subscribe
+pub struct SubscriptionHub {
+    epoch: i64,
+    limits: SubscriptionLimits,
+    state: Mutex<HubState>,
+}
+
+impl SubscriptionHub {
+    pub fn new(limits: SubscriptionLimits) -> Self {
+        Self {
+            epoch: Utc::now().timestamp_millis(),
+            state: Mutex::new(HubState {
+                next_seq: 0,
+                history: VecDeque::with_capacity(limits.history),
+                subscribers: vec![],
+            }),
+            limits,
+        }
+    }
+
+    // Messages after the cursor from the history, then the live ones.
+    // Without a cursor only the live messages are streamed.
+    pub fn subscribe(
+        &self,
+        sender: Option<Pattern>,
+        cursor: Option<Cursor>,
+    ) -> impl Stream<Item = Event> {
+        let mut state = self.state.lock().unwrap();
+
+        let after = cursor.map(|c| (c.epoch == self.epoch).then_some(c.seq));
+
+        let mut replay = vec![];
+        if let Some(after) = after {
+            let first = after.map(|seq| seq + 1).unwrap_or(0);
+            let oldest = state
+                .history
+                .front()
+                .map(|e| e.seq)
+                .unwrap_or(state.next_seq);
+            if first < oldest {
+                replay.push(Event::Lost {
+                    from: first,
+                    to: oldest - 1,
+                });
+            }
+
+            replay.extend(
+                state
+                    .history
+                    .iter()
+                    .filter(|e| e.seq >= first)
+                    .filter(|e| sender_matches(&sender, &e.sender))
+                    .map(|e| Event::Message {
+                        cursor: self.cursor(e.seq),
+                        json: e.json.clone(),
+                    }),
+            );
+        }
+
+        let (tx, rx) = mpsc::channel(self.limits.buffer);
+        state.subscribers.push(Subscriber {
+            sender,
+            tx,
+            lost: None,
+        });
+
+        stream::iter(replay).chain(ReceiverStream::new(rx))
+    }
+
+    fn cursor(&self, seq: u64) -> Cursor {
+        Cursor {
+            epoch: self.epoch,
+            seq,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSink for SubscriptionHub {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Subscribe
+    }
+
+    fn target(&self) -> &str {
+        "/subscribe"
+    }
+
+    // never waits for clients, slow ones are handled by the policy
+    async fn send(&self, message: &Message) -> Result<(), SendError> {
+        let json = Arc::new(serde_json::to_string(message).map_err(SendError::permanent)?);
+
+        let mut state = self.state.lock().unwrap();
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+
+        if self.limits.history > 0 {
+            if state.history.len() >= self.limits.history {
+                state.history.pop_front();
+            }
+            state.history.push_back(Entry {
+                seq,
+                sender: message.sender.clone(),
+                json: json.clone(),
+            });
+        }
+
+        let event = Event::Message {
+            cursor: self.cursor(seq),
+            json,
+        };
+        let policy = self.limits.slow_client;
+        state.subscribers.retain_mut(|s| {
+            if s.tx.is_closed() {
+                return false;
+            }
+            !sender_matches(&s.sender, &message.sender) || s.push(seq, event.clone(), policy)
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn hub(history: usize, buffer: usize, slow_client: SlowClientPolicy) -> SubscriptionHub {
+        SubscriptionHub::new(SubscriptionLimits {
+            history,
+            buffer,
+            slow_client,
+        })
+    }
+
+    fn message(n: usize) -> Message {
+        Message {
+            payload: format!("message {}", n),
+            has_error: false,
+            sender: "sender".to_owned(),
+            send_time: (1700000000000 + n).to_string(),
+            error: None,
+            partial: false,
+            gaps: vec![],
+        }
+    }
+
+    async fn send(hub: &SubscriptionHub, messages: std::ops::Range<usize>) {
+        for n in messages {
+            assert!(hub.send(&message(n)).await.is_ok());
+        }
+    }
+
+    // seq of a message event, the range of a lost one
+    fn describe(event: Option<Event>) -> String {
+        match event {
+            Some(Event::Message { cursor, .. }) => format!("message {}", cursor.seq),
+            Some(Event::Lost { from, to }) => format!("lost {}-{}", from, to),
+            None => "end".to_owned(),
+        }
+    }
+
+    #[test]
+    fn cursor_is_parsed_back() {
+        let cursor = Cursor {
+            epoch: 1700000000000,
+            seq: 42,
+        };
+
+        assert_eq!(cursor.to_string().parse::<Cursor>(), Ok(cursor));
+        assert!("42".parse::<Cursor>().is_err());
+        assert!("a-1".parse::<Cursor>().is_err());
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_cursor() {
+        let hub = hub(10, 10, SlowClientPolicy::Disconnect);
+        send(&hub, 0..3).await;
+
+        let mut stream = Box::pin(hub.subscribe(None, Some(hub.cursor(0))));
+        send(&hub, 3..4).await;
+
+        assert_eq!(describe(stream.next().await), "message 1");
+        assert_eq!(describe(stream.next().await), "message 2");
+        assert_eq!(describe(stream.next().await), "message 3");
+    }
+
+    #[tokio::test]
+    async fn cursor_before_the_history_reports_lost_messages() {
+        let hub = hub(2, 10, SlowClientPolicy::Disconnect);
+        send(&hub, 0..5).await;
+
+        let mut stream = Box::pin(hub.subscribe(None, Some(hub.cursor(0))));
+
+        assert_eq!(describe(stream.next().await), "lost 1-2");
+        assert_eq!(describe(stream.next().await), "message 3");
+        assert_eq!(describe(stream.next().await), "message 4");
+    }
+
+    #[tokio::test]
+    async fn cursor_of_another_epoch_resumes_from_the_start() {
+        let hub = hub(2, 10, SlowClientPolicy::Disconnect);
+        send(&hub, 0..5).await;
+
+        let stale = Cursor {
+            epoch: hub.epoch - 1,
+            seq: 4,
+        };
+        let mut stream = Box::pin(hub.subscribe(None, Some(stale)));
+
+        assert_eq!(describe(stream.next().await), "lost 0-2");
+        assert_eq!(describe(stream.next().await), "message 3");
+        assert_eq!(describe(stream.next().await), "message 4");
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_disconnected() {
+        let hub = hub(10, 1, SlowClientPolicy::Disconnect);
+        let mut stream = Box::pin(hub.subscribe(None, None));
+
+        send(&hub, 0..2).await;
+
+        assert_eq!(describe(stream.next().await), "message 0");
+        assert_eq!(describe(stream.next().await), "end");
+        assert!(hub.state.lock().unwrap().subscribers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_told_what_it_skipped() {
+        let hub = hub(10, 1, SlowClientPolicy::Skip);
+        let mut stream = Box::pin(hub.subscribe(None, None));
+
+        send(&hub, 0..3).await;
+        assert_eq!(describe(stream.next().await), "message 0");
+
+        // the lost range goes first once there is room, the next message is skipped again
+        send(&hub, 3..4).await;
+        assert_eq!(describe(stream.next().await), "lost 1-2");
+        send(&hub, 4..5).await;
+        assert_eq!(describe(stream.next().await), "lost 3-3");
+        // message 4 was skipped, the client stays subscribed
+        assert!(stream.next().now_or_never().is_none());
+        assert_eq!(hub.state.lock().unwrap().subscribers.len(), 1);
+    }
+}
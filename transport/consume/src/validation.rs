@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+
+use common::SegmentWithTime;
+
+// Checks of a single segment, consistency between segments of a message
+// is checked by the segments cache. seg_count is checked against the limit here,
+// the cache allocates a bitmap of seg_count for every new message.
+pub fn check(segment: &SegmentWithTime, max_seg_count: usize) -> Result<(), String> {
+    let seg = &segment.segment;
+
+    if seg.sender.is_empty() {
+        return Err("empty sender".to_owned());
+    }
+    if seg.seg_count == 0 {
+        return Err("seg_count is 0".to_owned());
+    }
+    if seg.seg_count > max_seg_count {
+        return Err(format!(
+            "{} segments, limit is {}",
+            seg.seg_count, max_seg_count
+        ));
+    }
+    if seg.seg_num >= seg.seg_count {
+        return Err(format!(
+            "seg_num {} is out of seg_count {}",
+            seg.seg_num, seg.seg_count
+        ));
+    }
+
+    let send_time_valid = segment
+        .send_time
+        .parse::<i64>()
+        .ok()
+        .and_then(DateTime::from_timestamp_millis)
+        .is_some();
+    if !send_time_valid {
+        return Err(format!(
+            "send_time {:?} is not a unix time in millis",
+            segment.send_time
+        ));
+    }
+
+    // payload is a part of utf-16 text
+    if !seg.payload.chunks_exact(2).remainder().is_empty() {
+        return Err(format!(
+            "payload of {} bytes is not utf-16",
+            seg.payload.len()
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub sender: Option<String>,
+    pub send_time: Option<String>,
+    pub reason: String,
+    pub quarantined_at: DateTime<Utc>,
+}
+
+// Last violating segments with reasons
+pub struct Quarantine {
+    entries: VecDeque<Violation>,
+    capacity: usize,
+}
+
+impl Quarantine {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn add(&mut self, violation: Violation) {
+        warn!(
+            "quarantined segment {}/{}@{} of {:?}/{:?}: {}",
+            violation.topic,
+            violation.partition,
+            violation.offset,
+            violation.sender,
+            violation.send_time,
+            violation.reason
+        );
+
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(violation);
+    }
+
+    pub fn entries(&self) -> Vec<Violation> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Segment;
+
+    use super::*;
+
+    fn segment(seg_num: usize, seg_count: usize) -> SegmentWithTime {
+        SegmentWithTime {
+            segment: Segment {
+                payload: vec![b'a', 0],
+                seg_count,
+                seg_num,
+                sender: "sender".to_owned(),
+            },
+            send_time: "1700000000000".to_owned(),
+        }
+    }
+
+    #[test]
+    fn valid_segment() {
+        assert!(check(&segment(0, 1), 10).is_ok());
+        assert!(check(&segment(9, 10), 10).is_ok());
+    }
+
+    #[test]
+    fn empty_sender() {
+        let mut seg = segment(0, 1);
+        seg.segment.sender.clear();
+
+        assert!(check(&seg, 10).is_err());
+    }
+
+    #[test]
+    fn seg_num_out_of_seg_count() {
+        assert!(check(&segment(0, 0), 10).is_err());
+        assert!(check(&segment(1, 1), 10).is_err());
+        assert!(check(&segment(usize::MAX, 1), 10).is_err());
+    }
+
+    #[test]
+    fn send_time_is_unix_millis() {
+        for send_time in ["", "yesterday", "1.5", "99999999999999999999"] {
+            let mut seg = segment(0, 1);
+            seg.send_time = send_time.to_owned();
+
+            assert!(check(&seg, 10).is_err(), "{:?}", send_time);
+        }
+    }
+
+    #[test]
+    fn odd_payload_is_not_utf16() {
+        let mut seg = segment(0, 1);
+        seg.segment.payload.push(b'b');
+        assert!(check(&seg, 10).is_err());
+
+        seg.segment.payload.clear();
+        assert!(check(&seg, 10).is_ok());
+    }
+
+    #[test]
+    fn seg_count_over_limit() {
+        assert!(check(&segment(0, 10), 10).is_ok());
+        assert!(check(&segment(0, 11), 10).is_err());
+        assert!(check(&segment(0, usize::MAX), 10).is_err());
+    }
+}
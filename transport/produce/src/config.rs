@@ -1,5 +1,12 @@
 use clap::Parser;
-use std::{env::var_os, ffi::OsStr};
+use std::{env::var_os, ffi::OsStr, time::Duration};
+
+use common::topic::TopicSpec;
+
+use crate::fault::FaultConfig;
+use crate::outbox::OutboxLimits;
+use crate::partitioner::PartitionStrategy;
+use crate::producer::{BackpressureLimits, DeliveryMode};
 
 const LISTEN_DEFAULT: &str = "0.0.0.0:8002";
 
@@ -9,23 +16,127 @@ struct Args {
     #[arg(short, long)]
     brokers: String,
 
+    /// default topic, used for segments not matched by any route
     #[arg(short, long)]
     topic: String,
+
+    /// json file with sender and header based routes to topics
+    #[arg(long)]
+    routes_file: Option<String>,
+
+    /// enable.idempotence, segments are not duplicated by producer retries
+    #[arg(long)]
+    idempotent: bool,
+
+    /// write each batch in a transaction with this transactional.id (implies --idempotent)
+    #[arg(long)]
+    transactional_id: Option<String>,
+
+    /// how segments are spread over partitions, all but `default` keep a message in one partition
+    #[arg(long, value_enum, default_value_t = PartitionStrategy::Default)]
+    partition_strategy: PartitionStrategy,
+
+    /// answer 503 when this many segments are waiting for kafka ack
+    #[arg(long, default_value_t = 10_000)]
+    max_in_flight_records: usize,
+
+    /// answer 503 when payloads waiting for kafka ack take this many bytes
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    max_in_flight_bytes: usize,
+
+    /// Retry-After value of 503 answers
+    #[arg(long, default_value_t = 1)]
+    retry_after_secs: u64,
+
+    /// directory of the on-disk outbox for segments accepted while kafka is unavailable
+    #[arg(long)]
+    outbox_dir: Option<String>,
+
+    /// size of the outbox log, the dead letter file next to it is rotated at the same size
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    outbox_max_bytes: u64,
+
+    #[arg(long, default_value_t = 100_000)]
+    outbox_max_records: usize,
+
+    /// create the topic on startup if it doesn't exist, fail if existing one doesn't match
+    #[arg(long)]
+    ensure_topic: bool,
+
+    #[arg(long, default_value_t = 1)]
+    topic_partitions: i32,
+
+    #[arg(long, default_value_t = 1)]
+    topic_replication_factor: i32,
+
+    #[arg(long)]
+    topic_retention_ms: Option<i64>,
+
+    #[arg(long)]
+    topic_cleanup_policy: Option<String>,
+
+    /// seed of the fault injection, same seed reproduces the same faults
+    #[arg(long, default_value_t = 0)]
+    fault_seed: u64,
+
+    /// probability to drop a segment
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    fault_drop: f64,
+
+    /// probability to produce a segment twice
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    fault_duplicate: f64,
+
+    /// probability to delay a segment, so it's reordered with the following ones
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    fault_delay: f64,
+
+    #[arg(long, default_value_t = 1000)]
+    fault_max_delay_ms: u64,
+
+    /// probability to flip a random bit of a segment payload
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    fault_bit_flip: f64,
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub listen: String,
+    pub grpc_listen: Option<String>,
     pub brokers: String,
     pub topic: String,
+    pub routes_file: Option<String>,
+    pub delivery_mode: DeliveryMode,
+    pub backpressure: BackpressureLimits,
+    pub partition_strategy: PartitionStrategy,
+    pub outbox_dir: Option<String>,
+    pub outbox_limits: OutboxLimits,
+    pub ensure_topic: Option<TopicSpec>,
+    pub faults: Option<FaultConfig>,
 }
 
 impl Config {
     pub fn build() -> Self {
         Self {
             listen: LISTEN_DEFAULT.to_owned(),
+            grpc_listen: None,
             brokers: "".to_string(),
             topic: "".to_string(),
+            routes_file: None,
+            delivery_mode: DeliveryMode::AtLeastOnce,
+            backpressure: BackpressureLimits {
+                max_in_flight_records: 0,
+                max_in_flight_bytes: 0,
+                retry_after: Duration::from_secs(0),
+            },
+            partition_strategy: PartitionStrategy::Default,
+            outbox_dir: None,
+            outbox_limits: OutboxLimits {
+                max_bytes: 0,
+                max_records: 0,
+            },
+            ensure_topic: None,
+            faults: None,
         }
     }
 
@@ -33,15 +144,56 @@ impl Config {
         let args = Args::parse();
         self.brokers = args.brokers;
         self.topic = args.topic;
+        self.routes_file = args.routes_file;
+        self.delivery_mode = match (args.transactional_id, args.idempotent) {
+            (Some(transactional_id), _) => DeliveryMode::Transactional(transactional_id),
+            (None, true) => DeliveryMode::Idempotent,
+            (None, false) => DeliveryMode::AtLeastOnce,
+        };
+        self.backpressure = BackpressureLimits {
+            max_in_flight_records: args.max_in_flight_records,
+            max_in_flight_bytes: args.max_in_flight_bytes,
+            retry_after: Duration::from_secs(args.retry_after_secs),
+        };
+        self.partition_strategy = args.partition_strategy;
+        self.outbox_dir = args.outbox_dir;
+        self.outbox_limits = OutboxLimits {
+            max_bytes: args.outbox_max_bytes,
+            max_records: args.outbox_max_records,
+        };
+        self.ensure_topic = args.ensure_topic.then_some(TopicSpec {
+            partitions: args.topic_partitions,
+            replication_factor: args.topic_replication_factor,
+            retention_ms: args.topic_retention_ms,
+            cleanup_policy: args.topic_cleanup_policy,
+        });
+        let faults = FaultConfig {
+            seed: args.fault_seed,
+            drop: args.fault_drop,
+            duplicate: args.fault_duplicate,
+            delay: args.fault_delay,
+            max_delay: Duration::from_millis(args.fault_max_delay_ms),
+            bit_flip: args.fault_bit_flip,
+        };
+        self.faults = faults.is_enabled().then_some(faults);
         self
     }
 
     pub fn get_env(mut self) -> Self {
         self.listen = env_or("LISTEN", LISTEN_DEFAULT.to_owned());
+        self.grpc_listen = var_os("GRPC_LISTEN").map(|os_str| os_str.into_string().unwrap());
         self
     }
 }
 
+fn probability(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=1.0).contains(&p) {
+        return Err(format!("{} is not in [0, 1]", p));
+    }
+    Ok(p)
+}
+
 fn env_or<K: AsRef<OsStr>>(key: K, default: String) -> String {
     var_os(key)
         .map(|os_str| os_str.into_string().unwrap())
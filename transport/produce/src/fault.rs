@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, warn};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use common::SegmentWithTime;
+
+use crate::producer::SegmentProducer;
+
+// Probabilities of every fault are checked independently for each segment
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    pub seed: u64,
+    pub drop: f64,
+    pub duplicate: f64,
+    pub delay: f64,
+    pub max_delay: Duration,
+    pub bit_flip: f64,
+}
+
+impl FaultConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.drop > 0.0 || self.duplicate > 0.0 || self.delay > 0.0 || self.bit_flip > 0.0
+    }
+}
+
+// Lossy channel simulator placed in front of kafka.
+// The same seed and the same order of incoming segments give the same faults.
+pub struct FaultInjector {
+    config: FaultConfig,
+    rng: Mutex<ChaCha8Rng>,
+    producer: Arc<SegmentProducer>,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig, producer: Arc<SegmentProducer>) -> Self {
+        let rng = ChaCha8Rng::seed_from_u64(config.seed);
+
+        Self {
+            config,
+            rng: Mutex::new(rng),
+            producer,
+        }
+    }
+
+    // Returns the segment to produce right away. Dropped and delayed segments give None,
+    // delayed copies and duplicates are produced in background.
+    pub fn inject(
+        &self,
+        topic_name: &str,
+        segment: SegmentWithTime,
+        partition: Option<i32>,
+    ) -> Option<SegmentWithTime> {
+        let mut segment = segment;
+        let mut rng = self.rng.lock().unwrap();
+
+        let id = format!(
+            "{}/{} seg {}/{}",
+            segment.segment.sender,
+            segment.send_time,
+            segment.segment.seg_num,
+            segment.segment.seg_count
+        );
+
+        if rng.gen_bool(self.config.drop) {
+            warn!("fault: dropped {}", id);
+            return None;
+        }
+
+        if !segment.segment.payload.is_empty() && rng.gen_bool(self.config.bit_flip) {
+            let byte = rng.gen_range(0..segment.segment.payload.len());
+            let bit = rng.gen_range(0..8);
+            segment.segment.payload[byte] ^= 1 << bit;
+            warn!("fault: flipped bit {} of byte {} in {}", bit, byte, id);
+        }
+
+        if rng.gen_bool(self.config.duplicate) {
+            let delay = self.random_delay(&mut rng);
+            warn!("fault: duplicated {}, copy delayed by {:?}", id, delay);
+            self.produce_later(topic_name, segment.clone(), partition, delay);
+        }
+
+        if rng.gen_bool(self.config.delay) {
+            let delay = self.random_delay(&mut rng);
+            warn!("fault: delayed {} by {:?}", id, delay);
+            self.produce_later(topic_name, segment, partition, delay);
+            return None;
+        }
+
+        Some(segment)
+    }
+
+    fn random_delay(&self, rng: &mut ChaCha8Rng) -> Duration {
+        let max_millis = self.config.max_delay.as_millis() as u64;
+        Duration::from_millis(rng.gen_range(0..=max_millis))
+    }
+
+    fn produce_later(
+        &self,
+        topic_name: &str,
+        segment: SegmentWithTime,
+        partition: Option<i32>,
+        delay: Duration,
+    ) {
+        let producer = self.producer.clone();
+        let topic_name = topic_name.to_owned();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = producer
+                .produce_segment(&topic_name, segment, partition)
+                .await
+            {
+                error!("fault: failed to produce delayed segment: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use tonic::metadata::MetadataValue;
+use tonic::{Request, Response, Status, Streaming};
+use warp::http::HeaderMap;
+
+use common::grpc::proto::produce_server::{Produce, ProduceServer};
+use common::grpc::proto::{SegmentWithTime, TransferReply, TransferStreamReply};
+
+use crate::transfer::{Transfer, TransferError, Transferred};
+
+// gRPC counterpart of the /transfer routes, metadata is routed the same way as http headers
+pub struct ProduceService {
+    transfer: Arc<Transfer>,
+}
+
+pub fn server(transfer: Arc<Transfer>) -> ProduceServer<ProduceService> {
+    ProduceServer::new(ProduceService { transfer })
+}
+
+#[tonic::async_trait]
+impl Produce for ProduceService {
+    async fn transfer(
+        &self,
+        request: Request<SegmentWithTime>,
+    ) -> Result<Response<TransferReply>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let partition = requested_partition(&headers).map_err(Status::invalid_argument)?;
+        let segment = request.into_inner().try_into()?;
+
+        match self.transfer.segment(segment, &headers, partition).await {
+            Ok(transferred) => Ok(Response::new(TransferReply {
+                stored_in_outbox: matches!(transferred, Transferred::StoredInOutbox),
+            })),
+            Err(e) => Err(status(e)),
+        }
+    }
+
+    async fn transfer_stream(
+        &self,
+        request: Request<Streaming<SegmentWithTime>>,
+    ) -> Result<Response<TransferStreamReply>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let partition = requested_partition(&headers).map_err(Status::invalid_argument)?;
+        let mut stream = request.into_inner();
+
+        let mut accepted = 0;
+        while let Some(segment) = stream.message().await? {
+            let segment = segment.try_into()?;
+
+            if let Err(e) = self.transfer.segment(segment, &headers, partition).await {
+                let e = status(e);
+                return Err(Status::with_metadata(
+                    e.code(),
+                    format!("{} (accepted {} segments)", e.message(), accepted),
+                    e.metadata().clone(),
+                ));
+            }
+
+            accepted += 1;
+        }
+
+        Ok(Response::new(TransferStreamReply { accepted }))
+    }
+}
+
+fn requested_partition(headers: &HeaderMap) -> Result<Option<i32>, String> {
+    match headers.get("x-partition") {
+        Some(v) => v
+            .to_str()
+            .ok()
+            .and_then(|v| v.parse::<i32>().ok())
+            .map(Some)
+            .ok_or_else(|| "invalid x-partition metadata".to_owned()),
+        None => Ok(None),
+    }
+}
+
+fn status(e: TransferError) -> Status {
+    match e {
+        TransferError::Overloaded {
+            reason,
+            retry_after,
+            queue_depth,
+        } => {
+            let mut status = Status::resource_exhausted(reason.to_string());
+            let metadata = status.metadata_mut();
+            metadata.insert("retry-after", retry_after.as_secs().max(1).into());
+            metadata.insert("x-queue-depth", MetadataValue::from(queue_depth as u64));
+            status
+        }
+        TransferError::OutboxFull { retry_after } => {
+            let mut status = Status::unavailable("outbox is full");
+            status
+                .metadata_mut()
+                .insert("retry-after", retry_after.as_secs().max(1).into());
+            status
+        }
+        TransferError::EmptyBatch => Status::invalid_argument("empty batch"),
+        TransferError::InvalidPartition(e) => Status::invalid_argument(e.to_string()),
+        TransferError::Failed(e) => Status::internal(e.to_string()),
+    }
+}
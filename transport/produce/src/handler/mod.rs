@@ -1,30 +1,61 @@
 use std::sync::Arc;
 
-use produce_segments::produce_segments;
+use produce_segments::{produce_batch, produce_segments};
+use queue_stats::queue_stats;
 use warp::{filters::BoxedFilter, Filter};
 
+use crate::outbox::Outbox;
 use crate::producer::SegmentProducer;
+use crate::transfer::Transfer;
 
 mod produce_segments;
+mod queue_stats;
 
 fn producer_filter(producer: Arc<SegmentProducer>) -> BoxedFilter<(Arc<SegmentProducer>,)> {
     warp::any().map(move || producer.clone()).boxed()
 }
 
-fn topic_name_filter(topic_name: String) -> BoxedFilter<(String,)> {
-    warp::any().map(move || topic_name.clone()).boxed()
+fn transfer_filter(transfer: Arc<Transfer>) -> BoxedFilter<(Arc<Transfer>,)> {
+    warp::any().map(move || transfer.clone()).boxed()
+}
+
+fn outbox_filter(outbox: Option<Arc<Outbox>>) -> BoxedFilter<(Option<Arc<Outbox>>,)> {
+    warp::any().map(move || outbox.clone()).boxed()
+}
+
+// partition requested by the client for the explicit partition strategy
+fn partition_filter() -> BoxedFilter<(Option<i32>,)> {
+    warp::header::optional::<i32>("x-partition").boxed()
 }
 
 pub fn routes(
+    transfer: Arc<Transfer>,
     producer: Arc<SegmentProducer>,
-    topic_name: String,
+    outbox: Option<Arc<Outbox>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    
+    let transfer_batch = warp::post()
+        .and(warp::path!("transfer" / "batch"))
+        .and(warp::body::json())
+        .and(warp::header::headers_cloned())
+        .and(partition_filter())
+        .and(transfer_filter(transfer.clone()))
+        .and_then(produce_batch);
 
-    warp::post()
+    let transfer = warp::post()
         .and(warp::path("transfer"))
+        .and(warp::path::end())
         .and(warp::body::json())
+        .and(warp::header::headers_cloned())
+        .and(partition_filter())
+        .and(transfer_filter(transfer))
+        .and_then(produce_segments);
+
+    let queue = warp::get()
+        .and(warp::path("queue"))
+        .and(warp::path::end())
         .and(producer_filter(producer))
-        .and(topic_name_filter(topic_name))
-        .and_then(produce_segments)
+        .and(outbox_filter(outbox))
+        .and_then(queue_stats);
+
+    transfer_batch.or(transfer).or(queue)
 }
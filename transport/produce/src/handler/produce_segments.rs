@@ -1,28 +1,90 @@
 use std::sync::Arc;
 
-use warp::{http, reply::Reply};
+use warp::{http, http::HeaderMap, reply::Reply};
 
 use common::SegmentWithTime;
 
-use crate::producer::SegmentProducer;
+use crate::transfer::{Transfer, TransferError, Transferred};
 
 pub async fn produce_segments(
     segment: SegmentWithTime,
-    producer: Arc<SegmentProducer>,
-    topic_name: String,
+    headers: HeaderMap,
+    partition: Option<i32>,
+    transfer: Arc<Transfer>,
 ) -> Result<warp::reply::Response, warp::Rejection> {
-    match producer.produce_segment(&topic_name, segment).await {
-        Ok(_) => {
-            Ok(
-                warp::reply::with_status("Segment sent", http::StatusCode::OK).into_response(),
-            )
+    match transfer.segment(segment, &headers, partition).await {
+        Ok(Transferred::Sent) => {
+            Ok(warp::reply::with_status("Segment sent", http::StatusCode::OK).into_response())
         }
-        Err(e) => {
-            Ok(warp::reply::with_status(
-                format!("Failed to send segment: {}", e),
-                http::StatusCode::INTERNAL_SERVER_ERROR,
-            )
-            .into_response())
+        Ok(Transferred::StoredInOutbox) => Ok(warp::reply::with_status(
+            "Segment stored in outbox",
+            http::StatusCode::ACCEPTED,
+        )
+        .into_response()),
+        Err(e) => Ok(failure_reply("Failed to send segment", e)),
+    }
+}
+
+pub async fn produce_batch(
+    segments: Vec<SegmentWithTime>,
+    headers: HeaderMap,
+    partition: Option<i32>,
+    transfer: Arc<Transfer>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let count = segments.len();
+
+    match transfer.batch(segments, &headers, partition).await {
+        Ok(Transferred::Sent) => Ok(warp::reply::with_status(
+            format!("Batch of {} segments sent", count),
+            http::StatusCode::OK,
+        )
+        .into_response()),
+        Ok(Transferred::StoredInOutbox) => Ok(warp::reply::with_status(
+            format!("Batch of {} segments stored in outbox", count),
+            http::StatusCode::ACCEPTED,
+        )
+        .into_response()),
+        Err(e) => Ok(failure_reply("Failed to send batch", e)),
+    }
+}
+
+// overloaded producer answers 503 with Retry-After so the client can throttle itself
+fn failure_reply(context: &str, e: TransferError) -> warp::reply::Response {
+    match e {
+        TransferError::Overloaded {
+            reason,
+            retry_after,
+            queue_depth,
+        } => {
+            let reply = warp::reply::with_status(
+                format!("{}: {}", context, reason),
+                http::StatusCode::SERVICE_UNAVAILABLE,
+            );
+            let reply = warp::reply::with_header(
+                reply,
+                http::header::RETRY_AFTER,
+                retry_after.as_secs().max(1).to_string(),
+            );
+            warp::reply::with_header(reply, "x-queue-depth", queue_depth.to_string())
+                .into_response()
+        }
+        TransferError::OutboxFull { retry_after } => warp::reply::with_header(
+            warp::reply::with_status("Outbox is full", http::StatusCode::SERVICE_UNAVAILABLE),
+            http::header::RETRY_AFTER,
+            retry_after.as_secs().max(1).to_string(),
+        )
+        .into_response(),
+        TransferError::EmptyBatch => {
+            warp::reply::with_status("Empty batch", http::StatusCode::BAD_REQUEST).into_response()
+        }
+        TransferError::InvalidPartition(e) => {
+            warp::reply::with_status(format!("{}: {}", context, e), http::StatusCode::BAD_REQUEST)
+                .into_response()
         }
+        TransferError::Failed(e) => warp::reply::with_status(
+            format!("{}: {}", context, e),
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
     }
 }
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::outbox::Outbox;
+use crate::producer::{QueueStats, SegmentProducer};
+
+#[derive(Serialize)]
+struct QueueState {
+    #[serde(flatten)]
+    producer: QueueStats,
+    outbox_depth: Option<usize>,
+}
+
+pub async fn queue_stats(
+    producer: Arc<SegmentProducer>,
+    outbox: Option<Arc<Outbox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let outbox_depth = match outbox {
+        Some(outbox) => Some(outbox.depth().await),
+        None => None,
+    };
+
+    Ok(warp::reply::json(&QueueState {
+        producer: producer.queue_stats(),
+        outbox_depth,
+    }))
+}
@@ -1,16 +1,26 @@
-use log::info;
+use fault::FaultInjector;
+use log::{error, info, warn};
+use outbox::Outbox;
 use producer::SegmentProducer;
+use router::TopicRouter;
 use std::{net::SocketAddr, sync::Arc};
-
+use tonic::transport::server::TcpIncoming;
+use transfer::Transfer;
 
 use config::Config;
 use handler::routes;
 
 mod config;
+mod fault;
+mod grpc;
 mod handler;
+mod outbox;
+mod partitioner;
 mod producer;
+mod router;
+mod transfer;
 
-use common::setup_env_logger;
+use common::{setup_env_logger, topic::ensure_topics};
 
 #[tokio::main]
 async fn main() {
@@ -20,10 +30,67 @@ async fn main() {
 
     info!("Config: {:?}", config);
 
-    let producer = SegmentProducer::new(&config.brokers);
+    let router = match &config.routes_file {
+        Some(path) => {
+            TopicRouter::from_file(path, config.topic.clone()).expect("Routes file error")
+        }
+        None => TopicRouter::single(config.topic.clone()),
+    };
+    info!("Routing to topics: {:?}", router.topics());
+
+    if let Some(spec) = &config.ensure_topic {
+        ensure_topics(&config.brokers, &router.topics(), spec)
+            .await
+            .expect("Topic provisioning error");
+    }
+
+    let producer = SegmentProducer::new(
+        &config.brokers,
+        &config.delivery_mode,
+        config.backpressure.clone(),
+        config.partition_strategy,
+    );
     let producer = Arc::new(producer);
 
-    warp::serve(routes(producer, config.topic))
+    let outbox = config.outbox_dir.as_ref().map(|dir| {
+        let outbox = Outbox::open(dir, config.outbox_limits.clone()).expect("Outbox open error");
+        Arc::new(outbox)
+    });
+
+    if let Some(outbox) = outbox.clone() {
+        tokio::spawn(outbox.drain(producer.clone()));
+    }
+
+    let faults = config.faults.clone().map(|faults| {
+        warn!("Fault injection is enabled: {:?}", faults);
+        Arc::new(FaultInjector::new(faults, producer.clone()))
+    });
+
+    let transfer = Arc::new(Transfer::new(
+        producer.clone(),
+        Arc::new(router),
+        outbox.clone(),
+        faults,
+    ));
+
+    if let Some(grpc_listen) = &config.grpc_listen {
+        let addr = grpc_listen.parse::<SocketAddr>().unwrap();
+        // bound here, so a taken address fails the startup instead of the spawned server
+        let incoming = TcpIncoming::new(addr, true, None).expect("gRPC listen error");
+        let server = tonic::transport::Server::builder()
+            .add_service(grpc::server(transfer.clone()))
+            .serve_with_incoming(incoming);
+
+        info!("gRPC listening on {}", addr);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("gRPC server error: {}", e);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    warp::serve(routes(transfer, producer, outbox))
         .run(config.listen.parse::<SocketAddr>().unwrap())
         .await;
 }
@@ -0,0 +1,540 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+
+use common::SegmentWithTime;
+
+use crate::producer::{self, SegmentProducer};
+
+const LOG_FILE_NAME: &str = "segments.log";
+const CURSOR_FILE_NAME: &str = "segments.cursor";
+const CURSOR_TMP_FILE_NAME: &str = "segments.cursor.tmp";
+const LOG_TMP_FILE_NAME: &str = "segments.log.tmp";
+// entries kafka refused for good, json lines with the reason
+const DEAD_LETTER_FILE_NAME: &str = "segments.dead";
+// the previous dead letter file, replaced when the current one reaches max_bytes
+const DEAD_LETTER_OLD_FILE_NAME: &str = "segments.dead.1";
+const DRAIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct OutboxLimits {
+    // size of the log file, the dead letter file is rotated at the same size
+    pub max_bytes: u64,
+    pub max_records: usize,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    // disk usage or depth limit is reached
+    Full,
+    Io(anyhow::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboxEntry {
+    topic: String,
+    segment: SegmentWithTime,
+    #[serde(default)]
+    partition: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    reason: String,
+    // line of the log as it was stored
+    entry: &'a str,
+}
+
+struct OutboxState {
+    log: File,
+    dir: PathBuf,
+    log_path: PathBuf,
+    cursor_path: PathBuf,
+    cursor_tmp_path: PathBuf,
+    dead_letter: File,
+    dead_letter_len: u64,
+    // byte offset of the first not drained entry
+    cursor: u64,
+    log_len: u64,
+    depth: usize,
+}
+
+// Append-only log of segments accepted while Kafka is unreachable.
+// Entries are json lines, the cursor file holds the offset of the first entry not yet produced.
+// Entries Kafka refuses for good are moved to the dead letter file so the rest can go on.
+// The drained beginning of the log is cut off when a push needs its space.
+pub struct Outbox {
+    state: Mutex<OutboxState>,
+    limits: OutboxLimits,
+    appended: Notify,
+}
+
+impl Outbox {
+    pub fn open(dir: impl AsRef<Path>, limits: OutboxLimits) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let log_path = dir.join(LOG_FILE_NAME);
+        let cursor_path = dir.join(CURSOR_FILE_NAME);
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        let mut log_len = log.metadata()?.len();
+
+        // a crash in the middle of a push leaves a cut line, the next entry starts on a new one
+        if log_len > 0 && last_byte(&log_path)? != b'\n' {
+            log.write_all(b"\n")?;
+            log.sync_data()?;
+            log_len += 1;
+        }
+
+        let dead_letter = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(DEAD_LETTER_FILE_NAME))?;
+        let dead_letter_len = dead_letter.metadata()?.len();
+
+        let cursor = match fs::read_to_string(&cursor_path) {
+            Ok(s) => s.trim().parse::<u64>()?.min(log_len),
+            Err(_) => 0,
+        };
+
+        let mut reader = BufReader::new(File::open(&log_path)?);
+        reader.seek(SeekFrom::Start(cursor))?;
+        let depth = reader.lines().count();
+
+        info!(
+            "outbox opened in {:?}: {} pending segments, {} bytes",
+            dir,
+            depth,
+            log_len - cursor
+        );
+
+        Ok(Self {
+            state: Mutex::new(OutboxState {
+                log,
+                dir: dir.to_owned(),
+                log_path,
+                cursor_path,
+                cursor_tmp_path: dir.join(CURSOR_TMP_FILE_NAME),
+                dead_letter,
+                dead_letter_len,
+                cursor,
+                log_len,
+                depth,
+            }),
+            limits,
+            appended: Notify::new(),
+        })
+    }
+
+    pub async fn depth(&self) -> usize {
+        self.state.lock().await.depth
+    }
+
+    pub async fn push(
+        &self,
+        topic: &str,
+        segment: SegmentWithTime,
+        partition: Option<i32>,
+    ) -> Result<(), PushError> {
+        self.push_all(vec![(topic.to_owned(), segment)], partition)
+            .await
+    }
+
+    // Stores all the segments or none of them
+    pub async fn push_all(
+        &self,
+        segments: Vec<(String, SegmentWithTime)>,
+        partition: Option<i32>,
+    ) -> Result<(), PushError> {
+        let count = segments.len();
+
+        let mut lines = vec![];
+        for (topic, segment) in segments {
+            let entry = OutboxEntry {
+                topic,
+                segment,
+                partition,
+            };
+            serde_json::to_writer(&mut lines, &entry).map_err(|e| PushError::Io(anyhow!(e)))?;
+            lines.push(b'\n');
+        }
+
+        let mut state = self.state.lock().await;
+
+        let size = lines.len() as u64;
+        if state.log_len + size > self.limits.max_bytes && state.cursor > 0 {
+            state.compact().map_err(PushError::Io)?;
+        }
+        if state.depth + count > self.limits.max_records
+            || state.log_len + size > self.limits.max_bytes
+        {
+            return Err(PushError::Full);
+        }
+
+        state
+            .log
+            .write_all(&lines)
+            .and_then(|_| state.log.sync_data())
+            .map_err(|e| PushError::Io(anyhow!(e)))?;
+
+        state.log_len += size;
+        state.depth += count;
+
+        self.appended.notify_one();
+
+        Ok(())
+    }
+
+    // Produces stored segments in order, blocks on the first one until Kafka accepts it.
+    // Entries Kafka refuses for good and broken ones go to the dead letter file.
+    pub async fn drain(self: Arc<Self>, producer: Arc<SegmentProducer>) {
+        loop {
+            let (line, next_cursor) = match self.peek().await {
+                Ok(Some(e)) => e,
+                Ok(None) => {
+                    self.appended.notified().await;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("outbox read failed: {}", e);
+                    tokio::time::sleep(DRAIN_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            let produced = match serde_json::from_str::<OutboxEntry>(&line) {
+                Ok(entry) => producer
+                    .produce_segment(&entry.topic, entry.segment, entry.partition)
+                    .await
+                    .map_err(|e| (producer::is_permanent(&e), e)),
+                Err(e) => Err((true, anyhow!("broken entry: {}", e))),
+            };
+
+            let result = match produced {
+                Ok(_) => self.advance(next_cursor).await,
+                Err((true, e)) => {
+                    warn!("outbox drain, moving entry to dead letter file: {}", e);
+                    self.dead_letter(&line, e.to_string(), next_cursor).await
+                }
+                Err((false, e)) => {
+                    warn!("outbox drain, kafka is still unavailable: {}", e);
+                    tokio::time::sleep(DRAIN_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            if let Err(e) = result {
+                warn!("outbox cursor update failed: {}", e);
+                tokio::time::sleep(DRAIN_RETRY_INTERVAL).await;
+            }
+        }
+    }
+
+    // the line of the first entry and the cursor after it
+    async fn peek(&self) -> Result<Option<(String, u64)>> {
+        let state = self.state.lock().await;
+        if state.depth == 0 {
+            return Ok(None);
+        }
+
+        let mut reader = BufReader::new(File::open(&state.log_path)?);
+        reader.seek(SeekFrom::Start(state.cursor))?;
+
+        let mut line = String::new();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            return Err(anyhow!("outbox log ends before its last entry"));
+        }
+
+        Ok(Some((
+            line.trim_end().to_owned(),
+            state.cursor + read as u64,
+        )))
+    }
+
+    async fn dead_letter(&self, line: &str, reason: String, next_cursor: u64) -> Result<()> {
+        let mut dead = serde_json::to_vec(&DeadLetter {
+            reason,
+            entry: line,
+        })?;
+        dead.push(b'\n');
+
+        {
+            let mut state = self.state.lock().await;
+            if state.dead_letter_len + dead.len() as u64 > self.limits.max_bytes {
+                state.rotate_dead_letter()?;
+            }
+            state.dead_letter.write_all(&dead)?;
+            state.dead_letter.sync_data()?;
+            state.dead_letter_len += dead.len() as u64;
+        }
+
+        self.advance(next_cursor).await
+    }
+
+    async fn advance(&self, next_cursor: u64) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.cursor = next_cursor;
+        state.depth -= 1;
+
+        // everything is drained, start the log from scratch to free the disk
+        if state.depth == 0 {
+            state.log.set_len(0)?;
+            state.log_len = 0;
+            state.cursor = 0;
+            info!("outbox drained");
+        }
+
+        state.write_cursor()
+    }
+}
+
+impl OutboxState {
+    // a crash leaves either the old cursor or the new one, never a cut file
+    fn write_cursor(&self) -> Result<()> {
+        let mut tmp = File::create(&self.cursor_tmp_path)?;
+        tmp.write_all(self.cursor.to_string().as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&self.cursor_tmp_path, &self.cursor_path)?;
+
+        Ok(())
+    }
+
+    // Moves the not drained entries to a new log. The cursor is reset before the new log
+    // replaces the old one, a crash in between produces the drained entries again.
+    fn compact(&mut self) -> Result<()> {
+        let tmp_path = self.dir.join(LOG_TMP_FILE_NAME);
+
+        let mut reader = File::open(&self.log_path)?;
+        reader.seek(SeekFrom::Start(self.cursor))?;
+        let mut tmp = File::create(&tmp_path)?;
+        let copied = std::io::copy(&mut reader, &mut tmp)?;
+        tmp.sync_all()?;
+
+        let drained = self.cursor;
+        self.cursor = 0;
+        self.write_cursor()?;
+        fs::rename(&tmp_path, &self.log_path)?;
+
+        self.log = OpenOptions::new().append(true).open(&self.log_path)?;
+        self.log_len = copied;
+        info!("outbox compacted, {} drained bytes freed", drained);
+
+        Ok(())
+    }
+
+    fn rotate_dead_letter(&mut self) -> Result<()> {
+        fs::rename(
+            self.dir.join(DEAD_LETTER_FILE_NAME),
+            self.dir.join(DEAD_LETTER_OLD_FILE_NAME),
+        )?;
+        self.dead_letter = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(DEAD_LETTER_FILE_NAME))?;
+        self.dead_letter_len = 0;
+        warn!(
+            "outbox dead letter file is full, older entries are kept in {}",
+            DEAD_LETTER_OLD_FILE_NAME
+        );
+
+        Ok(())
+    }
+}
+
+fn last_byte(path: &Path) -> Result<u8> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::End(-1))?;
+
+    let mut byte = [0u8];
+    file.read_exact(&mut byte)?;
+
+    Ok(byte[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Segment;
+
+    use super::*;
+
+    fn segment(seg_num: usize) -> SegmentWithTime {
+        SegmentWithTime {
+            segment: Segment {
+                payload: vec![b'a', 0],
+                seg_count: 3,
+                seg_num,
+                sender: "sender".to_owned(),
+            },
+            send_time: "1700000000000".to_owned(),
+        }
+    }
+
+    fn outbox_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("outbox-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn limits(max_records: usize) -> OutboxLimits {
+        OutboxLimits {
+            max_bytes: 1 << 20,
+            max_records,
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_is_stored_whole_or_not_at_all() {
+        let dir = outbox_dir("batch");
+        let outbox = Outbox::open(&dir, limits(2)).unwrap();
+
+        let batch = (0..3).map(|n| ("topic".to_owned(), segment(n))).collect();
+        assert!(matches!(
+            outbox.push_all(batch, None).await,
+            Err(PushError::Full)
+        ));
+        assert_eq!(outbox.depth().await, 0);
+
+        let batch = (0..2).map(|n| ("topic".to_owned(), segment(n))).collect();
+        assert!(outbox.push_all(batch, None).await.is_ok());
+        assert_eq!(outbox.depth().await, 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_lettered_entry_advances_the_cursor() {
+        let dir = outbox_dir("dead-letter");
+        let outbox = Outbox::open(&dir, limits(10)).unwrap();
+        outbox.push("topic", segment(0), None).await.unwrap();
+        outbox.push("topic", segment(1), None).await.unwrap();
+
+        let (line, next) = outbox.peek().await.unwrap().unwrap();
+        outbox
+            .dead_letter(&line, "refused".to_owned(), next)
+            .await
+            .unwrap();
+        drop(outbox);
+
+        let dead = fs::read_to_string(dir.join(DEAD_LETTER_FILE_NAME)).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+        assert!(dead.contains("refused"));
+
+        // reopened from the cursor, the second entry is next
+        let outbox = Outbox::open(&dir, limits(10)).unwrap();
+        assert_eq!(outbox.depth().await, 1);
+        let (line, _) = outbox.peek().await.unwrap().unwrap();
+        let entry: OutboxEntry = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry.segment.segment.seg_num, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cut_line_does_not_swallow_the_next_entry() {
+        let dir = outbox_dir("cut-line");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(LOG_FILE_NAME), "{\"topic\":\"top").unwrap();
+
+        let outbox = Outbox::open(&dir, limits(10)).unwrap();
+        outbox.push("topic", segment(0), None).await.unwrap();
+
+        let (line, next) = outbox.peek().await.unwrap().unwrap();
+        assert!(serde_json::from_str::<OutboxEntry>(&line).is_err());
+        outbox
+            .dead_letter(&line, "broken".to_owned(), next)
+            .await
+            .unwrap();
+
+        let (line, _) = outbox.peek().await.unwrap().unwrap();
+        assert!(serde_json::from_str::<OutboxEntry>(&line).is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drained_entries_make_room_for_new_ones() {
+        let dir = outbox_dir("compact");
+        let entry_len = serde_json::to_vec(&OutboxEntry {
+            topic: "topic".to_owned(),
+            segment: segment(0),
+            partition: None,
+        })
+        .unwrap()
+        .len() as u64
+            + 1;
+        let limits = OutboxLimits {
+            max_bytes: entry_len * 2,
+            max_records: 10,
+        };
+        let outbox = Outbox::open(&dir, limits.clone()).unwrap();
+        outbox.push("topic", segment(0), None).await.unwrap();
+        outbox.push("topic", segment(1), None).await.unwrap();
+        assert!(matches!(
+            outbox.push("topic", segment(2), None).await,
+            Err(PushError::Full)
+        ));
+
+        let (_, next) = outbox.peek().await.unwrap().unwrap();
+        outbox.advance(next).await.unwrap();
+        outbox.push("topic", segment(2), None).await.unwrap();
+        assert_eq!(
+            fs::metadata(dir.join(LOG_FILE_NAME)).unwrap().len(),
+            entry_len * 2
+        );
+        drop(outbox);
+
+        let outbox = Outbox::open(&dir, limits).unwrap();
+        assert_eq!(outbox.depth().await, 2);
+        let (line, _) = outbox.peek().await.unwrap().unwrap();
+        let entry: OutboxEntry = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry.segment.segment.seg_num, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_letter_file_is_rotated() {
+        let dir = outbox_dir("dead-letter-rotation");
+        let entry_len = serde_json::to_vec(&OutboxEntry {
+            topic: "topic".to_owned(),
+            segment: segment(0),
+            partition: None,
+        })
+        .unwrap()
+        .len() as u64
+            + 1;
+        let max_bytes = entry_len * 2;
+        let limits = OutboxLimits {
+            max_bytes,
+            max_records: 10,
+        };
+        let outbox = Outbox::open(&dir, limits).unwrap();
+
+        // a dead letter line holds the whole entry, two of them don't fit
+        for n in 0..3 {
+            outbox.push("topic", segment(n), None).await.unwrap();
+            let (line, next) = outbox.peek().await.unwrap().unwrap();
+            outbox
+                .dead_letter(&line, "refused".to_owned(), next)
+                .await
+                .unwrap();
+        }
+
+        assert!(fs::metadata(dir.join(DEAD_LETTER_FILE_NAME)).unwrap().len() <= max_bytes);
+        assert!(dir.join(DEAD_LETTER_OLD_FILE_NAME).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
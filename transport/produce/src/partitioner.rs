@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use rdkafka::producer::{FutureProducer, Producer};
+
+use common::SegmentWithTime;
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+// partitions can be added to a topic, so counts are refetched from time to time
+const PARTITION_COUNT_TTL: Duration = Duration::from_secs(60);
+
+// All strategies except `default` put every segment of a message into one partition
+// regardless of the record key, consume relies on it to reassemble messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PartitionStrategy {
+    // librdkafka partitioner over the record key (send_time)
+    Default,
+    // hash of (sender, send_time)
+    Message,
+    // hash of sender, all messages of a sender go to one partition
+    Sender,
+    // partition from the x-partition request header, `message` when it's absent
+    Explicit,
+}
+
+// Requested partition the topic doesn't have
+#[derive(Debug)]
+pub struct InvalidPartition(String);
+
+impl fmt::Display for InvalidPartition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPartition {}
+
+pub struct Partitioner {
+    strategy: PartitionStrategy,
+    partition_counts: RwLock<HashMap<String, (i32, Instant)>>,
+}
+
+impl Partitioner {
+    pub fn new(strategy: PartitionStrategy) -> Self {
+        Self {
+            strategy,
+            partition_counts: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // None leaves the choice to librdkafka
+    pub fn partition(
+        &self,
+        producer: &FutureProducer,
+        topic_name: &str,
+        segment: &SegmentWithTime,
+        requested: Option<i32>,
+    ) -> Result<Option<i32>> {
+        let hash = match (self.strategy, requested) {
+            (PartitionStrategy::Default, _) => return Ok(None),
+            (PartitionStrategy::Explicit, Some(partition)) => {
+                let count = self.partition_count(producer, topic_name)?;
+                if partition < 0 || partition >= count {
+                    bail!(InvalidPartition(format!(
+                        "partition {} is out of range, topic {} has {} partitions",
+                        partition, topic_name, count
+                    )));
+                }
+                return Ok(Some(partition));
+            }
+            (PartitionStrategy::Sender, _) => fnv1a(&[segment.segment.sender.as_bytes()]),
+            (PartitionStrategy::Message, _) | (PartitionStrategy::Explicit, None) => fnv1a(&[
+                segment.segment.sender.as_bytes(),
+                segment.send_time.as_bytes(),
+            ]),
+        };
+
+        let count = self.partition_count(producer, topic_name)?;
+
+        Ok(Some((hash % count as u64) as i32))
+    }
+
+    fn partition_count(&self, producer: &FutureProducer, topic_name: &str) -> Result<i32> {
+        if let Some((count, fetched_at)) = self.partition_counts.read().unwrap().get(topic_name) {
+            if fetched_at.elapsed() < PARTITION_COUNT_TTL {
+                return Ok(*count);
+            }
+        }
+
+        let metadata = tokio::task::block_in_place(|| {
+            producer
+                .client()
+                .fetch_metadata(Some(topic_name), METADATA_TIMEOUT)
+        })?;
+
+        let count = metadata
+            .topics()
+            .iter()
+            .find(|t| t.name() == topic_name)
+            .map(|t| t.partitions().len() as i32)
+            .filter(|count| *count > 0)
+            .ok_or_else(|| anyhow!("no partitions found for topic {}", topic_name))?;
+
+        self.partition_counts
+            .write()
+            .unwrap()
+            .insert(topic_name.to_owned(), (count, Instant::now()));
+
+        Ok(count)
+    }
+}
+
+// stable between builds and platforms unlike std hasher, parts are separated by 0 byte
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.iter().chain(&[0u8]) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Segment;
+    use rdkafka::ClientConfig;
+
+    use super::*;
+
+    const PARTITIONS: i32 = 4;
+
+    fn producer() -> FutureProducer {
+        ClientConfig::new()
+            .set("bootstrap.servers", "localhost:9")
+            .create()
+            .unwrap()
+    }
+
+    // partition count is cached so no broker is asked
+    fn partitioner(strategy: PartitionStrategy) -> Partitioner {
+        let partitioner = Partitioner::new(strategy);
+        partitioner
+            .partition_counts
+            .write()
+            .unwrap()
+            .insert("topic".to_owned(), (PARTITIONS, Instant::now()));
+        partitioner
+    }
+
+    fn segment(sender: &str, send_time: &str, seg_num: usize) -> SegmentWithTime {
+        SegmentWithTime {
+            segment: Segment {
+                payload: vec![],
+                seg_count: 8,
+                seg_num,
+                sender: sender.to_owned(),
+            },
+            send_time: send_time.to_owned(),
+        }
+    }
+
+    fn hash_partition(parts: &[&[u8]]) -> Option<i32> {
+        Some((fnv1a(parts) % PARTITIONS as u64) as i32)
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(fnv1a(&[]), 0xcbf29ce484222325);
+        // fnv-1a of "a\0"
+        assert_eq!(fnv1a(&[b"a"]), 0x089be207b544f1e4);
+        // parts are separated
+        assert_ne!(fnv1a(&[b"ab", b"c"]), fnv1a(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn default_strategy_leaves_partition_to_librdkafka() {
+        let producer = producer();
+        let partitioner = partitioner(PartitionStrategy::Default);
+
+        let partition = partitioner
+            .partition(&producer, "topic", &segment("s", "1", 0), Some(1))
+            .unwrap();
+        assert_eq!(partition, None);
+    }
+
+    #[test]
+    fn message_strategy_keeps_segments_together() {
+        let producer = producer();
+        let partitioner = partitioner(PartitionStrategy::Message);
+
+        let partitions: Vec<Option<i32>> = (0..8)
+            .map(|seg_num| {
+                partitioner
+                    .partition(&producer, "topic", &segment("s", "1", seg_num), None)
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(partitions
+            .iter()
+            .all(|p| *p == hash_partition(&[b"s", b"1"])));
+    }
+
+    #[test]
+    fn sender_strategy_ignores_send_time() {
+        let producer = producer();
+        let partitioner = partitioner(PartitionStrategy::Sender);
+
+        for send_time in ["1", "2", "3"] {
+            let partition = partitioner
+                .partition(&producer, "topic", &segment("s", send_time, 0), None)
+                .unwrap();
+            assert_eq!(partition, hash_partition(&[b"s"]));
+        }
+    }
+
+    #[test]
+    fn explicit_strategy_checks_the_range() {
+        let producer = producer();
+        let partitioner = partitioner(PartitionStrategy::Explicit);
+        let seg = segment("s", "1", 0);
+
+        assert_eq!(
+            partitioner
+                .partition(&producer, "topic", &seg, Some(3))
+                .unwrap(),
+            Some(3)
+        );
+        for requested in [-1, PARTITIONS] {
+            let e = partitioner
+                .partition(&producer, "topic", &seg, Some(requested))
+                .unwrap_err();
+            assert!(e.is::<InvalidPartition>());
+        }
+
+        // message strategy without the header
+        assert_eq!(
+            partitioner
+                .partition(&producer, "topic", &seg, None)
+                .unwrap(),
+            hash_partition(&[b"s", b"1"])
+        );
+    }
+}
@@ -1,37 +1,287 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use log::error;
 
-
-use rdkafka::producer::{FutureProducer};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{FutureProducer, Producer};
 use rdkafka::ClientConfig;
+use serde::Serialize;
+use tokio::sync::Mutex;
 
 use common::SegmentWithTime;
 
+use crate::partitioner::{InvalidPartition, PartitionStrategy, Partitioner};
+
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum DeliveryMode {
+    // plain producer, librdkafka retries may duplicate segments on the log
+    AtLeastOnce,
+    // enable.idempotence, no duplicates caused by internal retries
+    Idempotent,
+    // idempotent + every batch is written in a single transaction
+    Transactional(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct BackpressureLimits {
+    pub max_in_flight_records: usize,
+    pub max_in_flight_bytes: usize,
+    // suggested delay for the client when the producer is overloaded
+    pub retry_after: Duration,
+}
+
+// Returned when in-flight limits are reached or librdkafka queue is full,
+// the client is expected to retry later
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "producer queue is full")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+// Errors retrying can't fix: kafka refuses the segment or its topic or partition
+pub fn is_permanent(e: &anyhow::Error) -> bool {
+    if e.is::<InvalidPartition>() {
+        return true;
+    }
+
+    let code = match e.downcast_ref::<KafkaError>() {
+        Some(e) => e.rdkafka_error_code(),
+        None => return false,
+    };
+
+    matches!(
+        code,
+        Some(
+            RDKafkaErrorCode::InvalidMessage
+                | RDKafkaErrorCode::InvalidMessageSize
+                | RDKafkaErrorCode::MessageSizeTooLarge
+                | RDKafkaErrorCode::InvalidRecord
+                | RDKafkaErrorCode::UnknownTopic
+                | RDKafkaErrorCode::UnknownPartition
+                | RDKafkaErrorCode::UnknownTopicOrPartition
+                | RDKafkaErrorCode::InvalidTopic
+                | RDKafkaErrorCode::TopicAuthorizationFailed
+        )
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueStats {
+    pub in_flight_records: usize,
+    pub in_flight_bytes: usize,
+    pub max_in_flight_records: usize,
+    pub max_in_flight_bytes: usize,
+    // messages waiting in librdkafka queue or for the broker ack
+    pub kafka_queue_len: i32,
+}
+
 pub struct SegmentProducer {
     base: FutureProducer,
+    // only one transaction can be open on a producer at a time
+    transaction_lock: Option<Mutex<()>>,
+    limits: BackpressureLimits,
+    partitioner: Partitioner,
+    in_flight_records: AtomicUsize,
+    in_flight_bytes: AtomicUsize,
+}
+
+// Counts the segment as in-flight until dropped
+struct InFlight<'a> {
+    producer: &'a SegmentProducer,
+    bytes: usize,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.producer
+            .in_flight_records
+            .fetch_sub(1, Ordering::SeqCst);
+        self.producer
+            .in_flight_bytes
+            .fetch_sub(self.bytes, Ordering::SeqCst);
+    }
 }
 
 impl SegmentProducer {
-    pub fn new(brokers: &str) -> Self {
-        let producer: FutureProducer = ClientConfig::new()
+    pub fn new(
+        brokers: &str,
+        mode: &DeliveryMode,
+        limits: BackpressureLimits,
+        partition_strategy: PartitionStrategy,
+    ) -> Self {
+        let mut config = ClientConfig::new();
+        config
             .set("bootstrap.servers", brokers)
-            .set("message.timeout.ms", "5000")
-            .create()
-            .expect("Producer creation error");
+            .set("message.timeout.ms", "5000");
+
+        match mode {
+            DeliveryMode::AtLeastOnce => {}
+            DeliveryMode::Idempotent => {
+                config.set("enable.idempotence", "true");
+            }
+            DeliveryMode::Transactional(transactional_id) => {
+                config
+                    .set("enable.idempotence", "true")
+                    .set("transactional.id", transactional_id);
+            }
+        }
 
-        Self { base: producer }
+        let producer: FutureProducer = config.create().expect("Producer creation error");
+
+        let transaction_lock = match mode {
+            DeliveryMode::Transactional(_) => {
+                producer
+                    .init_transactions(TRANSACTION_TIMEOUT)
+                    .expect("Producer transactions init error");
+                Some(Mutex::new(()))
+            }
+            _ => None,
+        };
+
+        Self {
+            base: producer,
+            transaction_lock,
+            limits,
+            partitioner: Partitioner::new(partition_strategy),
+            in_flight_records: AtomicUsize::new(0),
+            in_flight_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        self.limits.retry_after
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            in_flight_records: self.in_flight_records.load(Ordering::SeqCst),
+            in_flight_bytes: self.in_flight_bytes.load(Ordering::SeqCst),
+            max_in_flight_records: self.limits.max_in_flight_records,
+            max_in_flight_bytes: self.limits.max_in_flight_bytes,
+            kafka_queue_len: self.base.in_flight_count(),
+        }
+    }
+
+    // partition is used only with the explicit partition strategy
+    pub async fn produce_segment(
+        &self,
+        topic_name: &str,
+        segment: SegmentWithTime,
+        partition: Option<i32>,
+    ) -> Result<()> {
+        // transactional producer can't send outside of a transaction
+        if self.transaction_lock.is_some() {
+            return self
+                .produce_batch(vec![(topic_name.to_owned(), segment)], partition)
+                .await;
+        }
+
+        self.send(topic_name, &segment, partition).await
+    }
+
+    // Segments with their topics. With transactional mode all segments of the batch are either
+    // committed together or aborted, so read_committed consumers never see a half of the batch.
+    // A transaction may span several topics.
+    pub async fn produce_batch(
+        &self,
+        segments: Vec<(String, SegmentWithTime)>,
+        partition: Option<i32>,
+    ) -> Result<()> {
+        let _guard = match &self.transaction_lock {
+            Some(lock) => lock.lock().await,
+            None => {
+                for (topic_name, segment) in segments.iter() {
+                    self.send(topic_name, segment, partition).await?;
+                }
+                return Ok(());
+            }
+        };
+
+        self.base.begin_transaction()?;
+
+        for (topic_name, segment) in segments.iter() {
+            if let Err(e) = self.send(topic_name, segment, partition).await {
+                tokio::task::block_in_place(|| self.base.abort_transaction(TRANSACTION_TIMEOUT))?;
+                return Err(e);
+            }
+        }
+
+        let committed =
+            tokio::task::block_in_place(|| self.base.commit_transaction(TRANSACTION_TIMEOUT));
+
+        // the transaction stays open after a failed commit, next batches couldn't begin theirs
+        if let Err(e) = committed {
+            match &e {
+                KafkaError::Transaction(txn) if txn.is_fatal() => {
+                    error!("transactional producer failed, restart required: {}", e);
+                }
+                _ => {
+                    if let Err(abort) = tokio::task::block_in_place(|| {
+                        self.base.abort_transaction(TRANSACTION_TIMEOUT)
+                    }) {
+                        error!("failed to abort transaction after failed commit: {}", abort);
+                    }
+                }
+            }
+            return Err(e.into());
+        }
+
+        Ok(())
     }
 
-    pub async fn produce_segment(&self, topic_name: &str, segment: SegmentWithTime) -> Result<()> {
+    async fn send(
+        &self,
+        topic_name: &str,
+        segment: &SegmentWithTime,
+        partition: Option<i32>,
+    ) -> Result<()> {
+        let partition = self
+            .partitioner
+            .partition(&self.base, topic_name, segment, partition)?;
+
+        let _in_flight = self.acquire(segment.segment.payload.len())?;
+
+        let record = segment.into_future_record(topic_name);
+        let record = match partition {
+            Some(partition) => record.partition(partition),
+            None => record,
+        };
+
         self.base
-            .send(
-                segment.into_future_record(topic_name),
-                Duration::from_secs(0),
-            )
+            .send(record, Duration::from_secs(0))
             .await
-            .map_err(|e| anyhow!(e.0))?;
+            .map_err(|e| match e.0 {
+                KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull) => anyhow!(Overloaded),
+                e => anyhow!(e),
+            })?;
 
         Ok(())
     }
+
+    fn acquire(&self, bytes: usize) -> Result<InFlight<'_>> {
+        let records = self.in_flight_records.fetch_add(1, Ordering::SeqCst);
+        let total_bytes = self.in_flight_bytes.fetch_add(bytes, Ordering::SeqCst);
+        let in_flight = InFlight {
+            producer: self,
+            bytes,
+        };
+
+        if records >= self.limits.max_in_flight_records
+            || total_bytes + bytes > self.limits.max_in_flight_bytes
+        {
+            return Err(anyhow!(Overloaded));
+        }
+
+        Ok(in_flight)
+    }
 }
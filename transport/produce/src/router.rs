@@ -0,0 +1,180 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use serde::Deserialize;
+use warp::http::HeaderMap;
+
+use common::{pattern::Pattern, SegmentWithTime};
+
+#[derive(Debug, Deserialize)]
+struct HeaderMatch {
+    name: String,
+    value: String,
+}
+
+// All the specified conditions have to match for the route to be chosen
+#[derive(Debug, Deserialize)]
+struct Route {
+    sender: Option<Pattern>,
+    header: Option<HeaderMatch>,
+    topic: String,
+}
+
+impl Route {
+    fn matches(&self, segment: &SegmentWithTime, headers: &HeaderMap) -> bool {
+        let sender_matches = match &self.sender {
+            Some(pattern) => pattern.matches(&segment.segment.sender),
+            None => true,
+        };
+
+        let header_matches = match &self.header {
+            Some(h) => headers
+                .get(&h.name)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v == h.value),
+            None => true,
+        };
+
+        sender_matches && header_matches
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RoutesFile {
+    default_topic: Option<String>,
+    #[serde(default)]
+    routes: Vec<Route>,
+}
+
+// Chooses the target topic of a segment, routes are checked in the file order
+#[derive(Debug)]
+pub struct TopicRouter {
+    default_topic: String,
+    routes: Vec<Route>,
+}
+
+impl TopicRouter {
+    pub fn single(topic: String) -> Self {
+        Self {
+            default_topic: topic,
+            routes: vec![],
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>, default_topic: String) -> Result<Self> {
+        let file: RoutesFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        Ok(Self {
+            default_topic: file.default_topic.unwrap_or(default_topic),
+            routes: file.routes,
+        })
+    }
+
+    pub fn route(&self, segment: &SegmentWithTime, headers: &HeaderMap) -> &str {
+        self.routes
+            .iter()
+            .find(|r| r.matches(segment, headers))
+            .map(|r| r.topic.as_str())
+            .unwrap_or(&self.default_topic)
+    }
+
+    pub fn topics(&self) -> Vec<&str> {
+        let mut topics = vec![self.default_topic.as_str()];
+        for route in self.routes.iter() {
+            if !topics.contains(&route.topic.as_str()) {
+                topics.push(&route.topic);
+            }
+        }
+        topics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Segment;
+    use warp::http::HeaderValue;
+
+    use super::*;
+
+    fn router(routes: &str) -> TopicRouter {
+        let file: RoutesFile = serde_json::from_str(routes).unwrap();
+
+        TopicRouter {
+            default_topic: file.default_topic.unwrap_or("default".to_owned()),
+            routes: file.routes,
+        }
+    }
+
+    fn segment(sender: &str) -> SegmentWithTime {
+        SegmentWithTime {
+            segment: Segment {
+                payload: vec![],
+                seg_count: 1,
+                seg_num: 0,
+                sender: sender.to_owned(),
+            },
+            send_time: "1700000000000".to_owned(),
+        }
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let router = router(
+            r#"{"routes": [
+                {"sender": {"exact": "team-a"}, "topic": "a"},
+                {"sender": {"glob": "team-*"}, "topic": "teams"},
+                {"sender": {"regex": "^team-a$"}, "topic": "never"}
+            ]}"#,
+        );
+
+        assert_eq!(router.route(&segment("team-a"), &HeaderMap::new()), "a");
+        assert_eq!(router.route(&segment("team-b"), &HeaderMap::new()), "teams");
+        assert_eq!(
+            router.route(&segment("other"), &HeaderMap::new()),
+            "default"
+        );
+    }
+
+    #[test]
+    fn header_and_sender_both_have_to_match() {
+        let router = router(
+            r#"{"default_topic": "fallback", "routes": [
+                {"sender": {"exact": "s"}, "header": {"name": "x-tenant", "value": "t1"}, "topic": "both"},
+                {"header": {"name": "x-tenant", "value": "t1"}, "topic": "tenant"}
+            ]}"#,
+        );
+
+        assert_eq!(
+            router.route(&segment("s"), &headers("x-tenant", "t1")),
+            "both"
+        );
+        assert_eq!(
+            router.route(&segment("other"), &headers("x-tenant", "t1")),
+            "tenant"
+        );
+        assert_eq!(
+            router.route(&segment("s"), &headers("x-tenant", "t2")),
+            "fallback"
+        );
+        assert_eq!(router.route(&segment("s"), &HeaderMap::new()), "fallback");
+    }
+
+    #[test]
+    fn topics_are_listed_once() {
+        let router = router(
+            r#"{"routes": [
+                {"sender": {"exact": "a"}, "topic": "x"},
+                {"sender": {"exact": "b"}, "topic": "x"},
+                {"sender": {"exact": "c"}, "topic": "default"}
+            ]}"#,
+        );
+
+        assert_eq!(router.topics(), vec!["default", "x"]);
+    }
+}
@@ -0,0 +1,196 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use warp::http::HeaderMap;
+
+use common::SegmentWithTime;
+
+use crate::fault::FaultInjector;
+use crate::outbox::{Outbox, PushError};
+use crate::partitioner::InvalidPartition;
+use crate::producer::{self, Overloaded, SegmentProducer};
+use crate::router::TopicRouter;
+
+pub enum Transferred {
+    Sent,
+    // kafka is unavailable, the segment will be produced by the outbox drain
+    StoredInOutbox,
+}
+
+pub enum TransferError {
+    Overloaded {
+        reason: anyhow::Error,
+        retry_after: Duration,
+        queue_depth: usize,
+    },
+    OutboxFull {
+        retry_after: Duration,
+    },
+    EmptyBatch,
+    // the requested partition doesn't exist
+    InvalidPartition(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+// Path of a segment from the API (http or grpc) to kafka
+pub struct Transfer {
+    producer: Arc<SegmentProducer>,
+    router: Arc<TopicRouter>,
+    outbox: Option<Arc<Outbox>>,
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl Transfer {
+    pub fn new(
+        producer: Arc<SegmentProducer>,
+        router: Arc<TopicRouter>,
+        outbox: Option<Arc<Outbox>>,
+        faults: Option<Arc<FaultInjector>>,
+    ) -> Self {
+        Self {
+            producer,
+            router,
+            outbox,
+            faults,
+        }
+    }
+
+    pub async fn segment(
+        &self,
+        segment: SegmentWithTime,
+        headers: &HeaderMap,
+        partition: Option<i32>,
+    ) -> Result<Transferred, TransferError> {
+        let topic_name = self.router.route(&segment, headers).to_owned();
+
+        // lossy channel acknowledges segments it has lost or delayed
+        let segment = match &self.faults {
+            Some(faults) => match faults.inject(&topic_name, segment, partition) {
+                Some(segment) => segment,
+                None => return Ok(Transferred::Sent),
+            },
+            None => segment,
+        };
+
+        let outbox = match &self.outbox {
+            Some(outbox) => outbox,
+            None => {
+                return self
+                    .producer
+                    .produce_segment(&topic_name, segment, partition)
+                    .await
+                    .map(|_| Transferred::Sent)
+                    .map_err(|e| self.produce_error(e))
+            }
+        };
+
+        // segments already waiting in the outbox go first to keep the order
+        if outbox.depth().await == 0 {
+            match self
+                .producer
+                .produce_segment(&topic_name, segment.clone(), partition)
+                .await
+            {
+                Ok(_) => return Ok(Transferred::Sent),
+                // the outbox only waits for kafka to come back, it can't fix these
+                Err(e) if e.is::<Overloaded>() || producer::is_permanent(&e) => {
+                    return Err(self.produce_error(e))
+                }
+                Err(e) => warn!("kafka is unavailable, storing segment in outbox: {}", e),
+            }
+        }
+
+        match outbox.push(&topic_name, segment, partition).await {
+            Ok(_) => Ok(Transferred::StoredInOutbox),
+            Err(PushError::Full) => Err(TransferError::OutboxFull {
+                retry_after: self.producer.retry_after(),
+            }),
+            Err(PushError::Io(e)) => Err(TransferError::Failed(e)),
+        }
+    }
+
+    // A batch kafka can't take is stored in the outbox whole, its segments are drained one
+    // by one, so a transactional batch loses its atomicity there
+    pub async fn batch(
+        &self,
+        segments: Vec<SegmentWithTime>,
+        headers: &HeaderMap,
+        partition: Option<i32>,
+    ) -> Result<Transferred, TransferError> {
+        if segments.is_empty() {
+            return Err(TransferError::EmptyBatch);
+        }
+
+        let segments: Vec<(String, SegmentWithTime)> = segments
+            .into_iter()
+            .map(|segment| (self.router.route(&segment, headers).to_owned(), segment))
+            .collect();
+
+        // every segment of the batch goes through the lossy channel on its own
+        let segments: Vec<(String, SegmentWithTime)> = match &self.faults {
+            Some(faults) => segments
+                .into_iter()
+                .filter_map(|(topic_name, segment)| {
+                    faults
+                        .inject(&topic_name, segment, partition)
+                        .map(|segment| (topic_name, segment))
+                })
+                .collect(),
+            None => segments,
+        };
+        if segments.is_empty() {
+            return Ok(Transferred::Sent);
+        }
+
+        let outbox = match &self.outbox {
+            Some(outbox) => outbox,
+            None => {
+                return self
+                    .producer
+                    .produce_batch(segments, partition)
+                    .await
+                    .map(|_| Transferred::Sent)
+                    .map_err(|e| self.produce_error(e))
+            }
+        };
+
+        // segments already waiting in the outbox go first to keep the order
+        if outbox.depth().await == 0 {
+            match self
+                .producer
+                .produce_batch(segments.clone(), partition)
+                .await
+            {
+                Ok(_) => return Ok(Transferred::Sent),
+                Err(e) if e.is::<Overloaded>() || producer::is_permanent(&e) => {
+                    return Err(self.produce_error(e))
+                }
+                Err(e) => warn!("kafka is unavailable, storing batch in outbox: {}", e),
+            }
+        }
+
+        match outbox.push_all(segments, partition).await {
+            Ok(_) => Ok(Transferred::StoredInOutbox),
+            Err(PushError::Full) => Err(TransferError::OutboxFull {
+                retry_after: self.producer.retry_after(),
+            }),
+            Err(PushError::Io(e)) => Err(TransferError::Failed(e)),
+        }
+    }
+
+    fn produce_error(&self, e: anyhow::Error) -> TransferError {
+        if e.is::<InvalidPartition>() {
+            return TransferError::InvalidPartition(e);
+        }
+        if !e.is::<Overloaded>() {
+            return TransferError::Failed(e);
+        }
+
+        TransferError::Overloaded {
+            reason: e,
+            retry_after: self.producer.retry_after(),
+            queue_depth: self.producer.queue_stats().in_flight_records,
+        }
+    }
+}
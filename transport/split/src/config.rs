@@ -18,6 +18,7 @@ struct Args {
 #[derive(Debug, Clone)]
 pub struct Config {
     pub listen: String,
+    pub grpc_listen: Option<String>,
     pub code_service_url: String,
     pub chunk_byte_size: usize,
 }
@@ -26,6 +27,7 @@ impl Config {
     pub fn build() -> Self {
         Self {
             listen: LISTEN_DEFAULT.to_owned(),
+            grpc_listen: None,
             code_service_url: CODE_SERVICE_URL_DEFAULT.to_owned(),
             chunk_byte_size: CHUNK_BYTE_SIZE_DEFAULT.to_owned(),
         }
@@ -40,6 +42,7 @@ impl Config {
 
     pub fn get_env(mut self) -> Self {
         self.listen = env_or("LISTEN", LISTEN_DEFAULT.to_owned());
+        self.grpc_listen = var_os("GRPC_LISTEN").map(|os_str| os_str.into_string().unwrap());
         self
     }
 }
@@ -0,0 +1,36 @@
+use log::info;
+use tonic::{Request, Response, Status};
+
+use common::grpc::proto::split_server::{Split, SplitServer};
+use common::grpc::proto::{Message, SendReply};
+
+use crate::handler::send_segments;
+
+// gRPC counterpart of the /send route
+pub struct SplitService {
+    code_service_url: String,
+    chunk_byte_size: usize,
+}
+
+pub fn server(code_service_url: String, chunk_byte_size: usize) -> SplitServer<SplitService> {
+    SplitServer::new(SplitService {
+        code_service_url,
+        chunk_byte_size,
+    })
+}
+
+#[tonic::async_trait]
+impl Split for SplitService {
+    async fn send(&self, request: Request<Message>) -> Result<Response<SendReply>, Status> {
+        let m = crate::handler::Message::from(request.into_inner());
+        info!("grpc send recieved: {:?}", &m);
+
+        let segments_sent = send_segments(m, &self.code_service_url, self.chunk_byte_size)
+            .await
+            .map_err(Status::unavailable)?;
+
+        Ok(Response::new(SendReply {
+            segments_sent: segments_sent as u64,
+        }))
+    }
+}
@@ -1,5 +1,6 @@
-use reqwest::{IntoUrl};
+use reqwest::IntoUrl;
 use send_message::send_message;
+pub use send_message::{send_segments, Message};
 use warp::{filters::BoxedFilter, Filter};
 
 mod send_message;
@@ -21,8 +22,6 @@ pub fn routes(
     code_service_url: impl ThreadSafeIntoUrl,
     chunk_size: usize,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    
-
     warp::post()
         .and(warp::path("send"))
         .and(warp::path::end())
@@ -1,9 +1,9 @@
 use log::info;
 use reqwest::{self, IntoUrl};
 use serde::{Deserialize, Serialize};
-use warp::{http, reply::Reply, Filter};
+use warp::{http, reply::Reply};
 
-use common::{Segment, SegmentWithTime};
+use common::{grpc::proto, Segment, SegmentWithTime};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message {
@@ -12,6 +12,16 @@ pub struct Message {
     payload: String,
 }
 
+impl From<proto::Message> for Message {
+    fn from(value: proto::Message) -> Self {
+        Self {
+            sender: value.sender,
+            send_time: value.send_time,
+            payload: value.payload,
+        }
+    }
+}
+
 fn split_message(m: Message, chunk_byte_size: usize) -> Vec<SegmentWithTime> {
     let payload_u16: Vec<u16> = m.payload.encode_utf16().collect();
     let payload_bytes: &[u8] = unsafe { payload_u16.align_to().1 };
@@ -38,41 +48,48 @@ fn split_message(m: Message, chunk_byte_size: usize) -> Vec<SegmentWithTime> {
         .collect()
 }
 
-pub async fn send_message(
+// splits the message and posts segments to the code service one by one,
+// returns the number of sent segments
+pub async fn send_segments(
     m: Message,
     code_service_url: impl IntoUrl,
     chunk_byte_size: usize,
-) -> Result<warp::reply::Response, warp::Rejection> {
-    info!("send_message recieved: {:?}", &m);
+) -> Result<usize, String> {
     let segments = split_message(m, chunk_byte_size);
+    let count = segments.len();
 
     let client = reqwest::Client::new();
 
-    let url = code_service_url.into_url().unwrap();
+    let url = code_service_url.into_url().map_err(|e| e.to_string())?;
 
     for segment in segments {
         let resp = match client.post(url.clone()).json(&segment).send().await {
             Ok(resp) => resp,
-            Err(e) => {
-                return Ok(warp::reply::with_status(
-                    format!("Failed to send segment: {}", e),
-                    http::StatusCode::INTERNAL_SERVER_ERROR,
-                )
-                .into_response());
-            }
+            Err(e) => return Err(format!("Failed to send segment: {}", e)),
         };
 
         if !resp.status().is_success() {
-            return Ok(warp::reply::with_status(
-                "Failed to send segment",
-                http::StatusCode::INTERNAL_SERVER_ERROR,
-            )
-            .into_response());
+            return Err("Failed to send segment".to_owned());
         }
     }
 
-    Ok(
-        warp::reply::with_status("Segments sended successfully", http::StatusCode::OK)
-            .into_response(),
-    )
+    Ok(count)
+}
+
+pub async fn send_message(
+    m: Message,
+    code_service_url: impl IntoUrl,
+    chunk_byte_size: usize,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    info!("send_message recieved: {:?}", &m);
+
+    match send_segments(m, code_service_url, chunk_byte_size).await {
+        Ok(_) => Ok(
+            warp::reply::with_status("Segments sended successfully", http::StatusCode::OK)
+                .into_response(),
+        ),
+        Err(e) => Ok(
+            warp::reply::with_status(e, http::StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+        ),
+    }
 }
@@ -4,9 +4,11 @@ use config::Config;
 use handler::routes;
 
 use common::setup_env_logger;
-use log::info;
+use log::{error, info};
+use tonic::transport::server::TcpIncoming;
 
 mod config;
+mod grpc;
 mod handler;
 
 #[tokio::main]
@@ -18,6 +20,26 @@ async fn main() {
 
     info!("Config: {:?}", config);
 
+    if let Some(grpc_listen) = &config.grpc_listen {
+        let addr = grpc_listen.parse::<SocketAddr>().unwrap();
+        // bound here, so a taken address fails the startup instead of the spawned server
+        let incoming = TcpIncoming::new(addr, true, None).expect("gRPC listen error");
+        let server = tonic::transport::Server::builder()
+            .add_service(grpc::server(
+                config.code_service_url.clone(),
+                config.chunk_byte_size,
+            ))
+            .serve_with_incoming(incoming);
+
+        info!("gRPC listening on {}", addr);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("gRPC server error: {}", e);
+                std::process::exit(1);
+            }
+        });
+    }
+
     warp::serve(routes(config.code_service_url, config.chunk_byte_size))
         .run(config.listen.parse::<SocketAddr>().unwrap())
         .await;